@@ -0,0 +1,48 @@
+use std::process::Command;
+
+fn main() {
+    // Allow the commit to be provided directly, for builds without a git
+    // checkout (e.g. container builds).
+    println!("cargo:rerun-if-env-changed=LIBRETTO_GIT_COMMIT");
+    let git_commit = std::env::var("LIBRETTO_GIT_COMMIT")
+        .ok()
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=LIBRETTO_GIT_COMMIT={git_commit}");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let matrix_sdk_version = locked_version("matrix-sdk").unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=LIBRETTO_MATRIX_SDK_VERSION={matrix_sdk_version}");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+/// Find the version of a package in `Cargo.lock`.
+fn locked_version(package: &str) -> Option<String> {
+    let lock = std::fs::read_to_string("Cargo.lock").ok()?;
+    lock.split("[[package]]").find_map(|entry| {
+        let mut name = None;
+        let mut version = None;
+        for line in entry.lines() {
+            if let Some(value) = line.strip_prefix("name = ") {
+                name = Some(value.trim_matches('"'));
+            } else if let Some(value) = line.strip_prefix("version = ") {
+                version = Some(value.trim_matches('"'));
+            }
+        }
+        if name == Some(package) {
+            version.map(ToOwned::to_owned)
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,27 @@
+use serde::Serialize;
+
+/// The version of libretto.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The default user agent sent to the homeserver.
+pub const USER_AGENT: &str = concat!("libretto/", env!("CARGO_PKG_VERSION"));
+
+/// The git commit libretto was built from.
+pub const GIT_COMMIT: &str = env!("LIBRETTO_GIT_COMMIT");
+
+/// The version of matrix-sdk libretto was built with.
+pub const MATRIX_SDK_VERSION: &str = env!("LIBRETTO_MATRIX_SDK_VERSION");
+
+/// Information about the running build, for bug reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub matrix_sdk_version: &'static str,
+}
+
+pub const BUILD_INFO: BuildInfo = BuildInfo {
+    version: VERSION,
+    git_commit: GIT_COMMIT,
+    matrix_sdk_version: MATRIX_SDK_VERSION,
+};
@@ -0,0 +1,122 @@
+//! Validators for responses, and conditional requests against them, so that
+//! browsers and caching proxies can check their copy of a page or media is
+//! still current without downloading it again.
+
+use axum::{
+    http::{self, HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use jiff::{Timestamp, fmt::rfc2822};
+use sha2::{Digest, Sha256};
+
+/// An entity tag for a response body, from a hash of it.
+pub fn body_etag(body: &[u8]) -> String {
+    let hash = Sha256::digest(body);
+    format!("\"{}\"", BASE64_URL_SAFE_NO_PAD.encode(&hash[..16]))
+}
+
+/// Whether the client's copy is still current, going by `If-None-Match` if it
+/// was sent and `If-Modified-Since` otherwise.
+///
+/// Either validator may be left out if the response doesn't have it, in which
+/// case conditions on it never match.
+pub fn is_not_modified(
+    headers: &HeaderMap,
+    etag: Option<&str>,
+    last_modified: Option<Timestamp>,
+) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return etag.is_some_and(|etag| etag_matches(if_none_match, etag));
+    }
+    let if_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| rfc2822::DateTimeParser::new().parse_timestamp(value).ok());
+    match (last_modified, if_modified_since) {
+        // HTTP dates only have whole seconds.
+        (Some(last_modified), Some(since)) => last_modified.as_second() <= since.as_second(),
+        _ => false,
+    }
+}
+
+/// Whether an `If-None-Match` header lists `etag`, or is `*`.
+///
+/// Tags are compared weakly, as `If-None-Match` requires.
+fn etag_matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// The `ETag` and `Last-Modified` headers for a response.
+pub fn validators(etag: Option<&str>, last_modified: Option<Timestamp>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(last_modified) = last_modified
+        .and_then(|ts| {
+            rfc2822::DateTimePrinter::new()
+                .timestamp_to_rfc9110_string(&ts)
+                .ok()
+        })
+        .and_then(|date| HeaderValue::from_str(&date).ok())
+    {
+        headers.insert(header::LAST_MODIFIED, last_modified);
+    }
+    headers
+}
+
+/// A `304 Not Modified` response, which repeats the validators so the client
+/// can update the ones it stored.
+pub fn not_modified(validators: HeaderMap) -> Response {
+    (http::StatusCode::NOT_MODIFIED, validators).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(name: header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn if_none_match_is_compared_weakly() {
+        let etag = "\"abc\"";
+        for if_none_match in ["\"abc\"", "W/\"abc\"", "\"xyz\", \"abc\"", "*"] {
+            let headers = request(header::IF_NONE_MATCH, if_none_match);
+            assert!(
+                is_not_modified(&headers, Some(etag), None),
+                "{if_none_match}"
+            );
+        }
+        let headers = request(header::IF_NONE_MATCH, "\"xyz\"");
+        assert!(!is_not_modified(&headers, Some(etag), None));
+    }
+
+    #[test]
+    fn if_modified_since_is_only_checked_without_if_none_match() {
+        let last_modified: Timestamp = "2024-01-15T12:00:00Z".parse().unwrap();
+        let headers = request(header::IF_MODIFIED_SINCE, "Mon, 15 Jan 2024 12:00:00 GMT");
+        assert!(is_not_modified(&headers, None, Some(last_modified)));
+        let headers = request(header::IF_MODIFIED_SINCE, "Mon, 15 Jan 2024 11:59:59 GMT");
+        assert!(!is_not_modified(&headers, None, Some(last_modified)));
+
+        let mut headers = request(header::IF_MODIFIED_SINCE, "Mon, 15 Jan 2024 12:00:00 GMT");
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+        assert!(!is_not_modified(
+            &headers,
+            Some("\"abc\""),
+            Some(last_modified)
+        ));
+    }
+}
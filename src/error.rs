@@ -0,0 +1,187 @@
+//! Errors from handling a request, and how they are shown to viewers.
+
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Request, State},
+    http,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use color_eyre::eyre;
+use ruma::api::client::error::ErrorKind;
+use tracing::{error, warn};
+
+use crate::{Config, room_to_html::ErrorTemplate};
+
+/// An error from handling a request, by what went wrong.
+///
+/// Each kind maps to a status code and a short message for the error page.
+/// The report itself is logged, and only shown on the page with
+/// `--verbose-errors`. The page is rendered by [`render_error_pages`], which
+/// has the configuration for it.
+#[derive(Debug)]
+pub enum AppError {
+    /// The request was malformed, such as an invalid room ID or date.
+    BadRequest(eyre::Report),
+    /// What was asked for doesn't exist, or isn't served by this archive.
+    NotFound(eyre::Report),
+    /// The request isn't allowed, by the homeserver or by the archive.
+    Forbidden(eyre::Report),
+    /// The homeserver couldn't be reached or failed the request.
+    Upstream(eyre::Report),
+    /// The homeserver didn't answer in time.
+    Timeout(eyre::Report),
+    /// The homeserver would have to be asked, but the archive is being served
+    /// offline.
+    Offline(eyre::Report),
+    /// Anything else.
+    Internal(eyre::Report),
+}
+
+impl AppError {
+    pub fn status(&self) -> http::StatusCode {
+        match self {
+            Self::BadRequest(_) => http::StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => http::StatusCode::NOT_FOUND,
+            Self::Forbidden(_) => http::StatusCode::FORBIDDEN,
+            Self::Upstream(_) => http::StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => http::StatusCode::GATEWAY_TIMEOUT,
+            Self::Offline(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What went wrong, in terms a viewer can act on.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "The link you followed isn't valid.",
+            Self::NotFound(_) => "This page doesn't exist, or isn't part of this archive.",
+            Self::Forbidden(_) => "This archive isn't allowed to show this page.",
+            Self::Upstream(_) => "The homeserver couldn't be reached or refused the request.",
+            Self::Timeout(_) => "The homeserver took too long to respond. Try again shortly.",
+            Self::Offline(_) => "This isn't stored locally.",
+            Self::Internal(_) => "Something went wrong.",
+        }
+    }
+
+    fn report(&self) -> &eyre::Report {
+        match self {
+            Self::BadRequest(report)
+            | Self::NotFound(report)
+            | Self::Forbidden(report)
+            | Self::Upstream(report)
+            | Self::Timeout(report)
+            | Self::Offline(report)
+            | Self::Internal(report) => report,
+        }
+    }
+
+    /// Classify an error that wasn't given a kind explicitly, by whether it
+    /// came from the homeserver.
+    fn classify(report: eyre::Report) -> Self {
+        match homeserver_error(&report) {
+            Some(kind) => Self::from_error_kind(kind, report),
+            None => Self::Internal(report),
+        }
+    }
+
+    fn from_error_kind(kind: Option<ErrorKind>, report: eyre::Report) -> Self {
+        match kind {
+            Some(ErrorKind::NotFound) => Self::NotFound(report),
+            Some(ErrorKind::Forbidden { .. }) => Self::Forbidden(report),
+            _ => Self::Upstream(report),
+        }
+    }
+}
+
+/// The error code of a failed request to the homeserver, if one caused the
+/// report anywhere in its chain, so wrapping it with context doesn't hide it.
+fn homeserver_error(report: &eyre::Report) -> Option<Option<ErrorKind>> {
+    report.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<matrix_sdk::HttpError>() {
+            return Some(err.client_api_error_kind().cloned());
+        }
+        let err = cause.downcast_ref::<matrix_sdk::Error>()?;
+        let kind = err.client_api_error_kind().cloned();
+        (kind.is_some() || matches!(err, matrix_sdk::Error::Http(_))).then_some(kind)
+    })
+}
+
+/// An error page still to be rendered, left on the response for
+/// [`render_error_pages`].
+#[derive(Clone)]
+struct PendingErrorPage {
+    message: &'static str,
+    report: Arc<eyre::Report>,
+    offline: bool,
+}
+
+// Tell axum how to convert `AppError` into a response.
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!("Request failed with {status}: {:?}", self.report());
+        } else {
+            warn!("Request failed with {status}: {}", self.report());
+        }
+
+        let message = self.message();
+        let offline = matches!(self, Self::Offline(_));
+        let report = match self {
+            Self::BadRequest(report)
+            | Self::NotFound(report)
+            | Self::Forbidden(report)
+            | Self::Upstream(report)
+            | Self::Timeout(report)
+            | Self::Offline(report)
+            | Self::Internal(report) => report,
+        };
+        let mut response = (status, message).into_response();
+        response.extensions_mut().insert(PendingErrorPage {
+            message,
+            report: Arc::new(report),
+            offline,
+        });
+        response
+    }
+}
+
+/// Render the error page for responses from an [`AppError`], in place of
+/// their plain text message.
+pub async fn render_error_pages(
+    State(config): State<Arc<Config>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    let Some(page) = response.extensions_mut().remove::<PendingErrorPage>() else {
+        return response;
+    };
+    let status = response.status();
+    let template = ErrorTemplate {
+        appearance: config.appearance(),
+        status,
+        message: page.message,
+        detail: config
+            .server_config
+            .verbose_errors
+            .then(|| format!("{:?}", page.report)),
+        offline: page.offline,
+    };
+    match template.render() {
+        Ok(html) => (status, axum::response::Html(html)).into_response(),
+        Err(_) => response,
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<eyre::Report>,
+{
+    fn from(err: E) -> Self {
+        Self::classify(err.into())
+    }
+}
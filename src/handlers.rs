@@ -0,0 +1,91 @@
+//! Handlers for events received through sync.
+
+use std::time::Duration;
+
+use matrix_sdk::{Client, Room, event_handler::Ctx};
+use ruma::{
+    UserId,
+    events::room::member::{MembershipState, StrippedRoomMemberEvent},
+};
+use tracing::{info, warn};
+
+/// The longest to keep retrying a join before giving up.
+const MAX_JOIN_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Who the account accepts room invites from.
+#[derive(Clone, Debug)]
+pub struct AutojoinPolicy {
+    /// User IDs and server names to accept invites from, or `*` for anyone.
+    /// Empty accepts no invites.
+    pub allowed_inviters: Vec<String>,
+}
+
+impl AutojoinPolicy {
+    /// Whether an invite from `inviter` should be accepted.
+    ///
+    /// Entries starting with `@` match a user ID exactly, `*` matches
+    /// everyone, and other entries match every user on that server.
+    pub fn allows(&self, inviter: &UserId) -> bool {
+        self.allowed_inviters.iter().any(|allowed| {
+            if allowed == "*" {
+                true
+            } else if allowed.starts_with('@') {
+                allowed == inviter.as_str()
+            } else {
+                allowed == inviter.server_name().as_str()
+            }
+        })
+    }
+}
+
+/// Accept invites to rooms from allowed inviters.
+///
+/// This runs for invites received before startup too.
+pub async fn on_stripped_state_member(
+    room_member: StrippedRoomMemberEvent,
+    client: Client,
+    room: Room,
+    Ctx(policy): Ctx<AutojoinPolicy>,
+) {
+    if client.user_id() != Some(&*room_member.state_key) {
+        return;
+    }
+    // Rooms we've knocked on also send our own member event as stripped
+    // state, and there is nothing to accept there.
+    if room_member.content.membership != MembershipState::Invite {
+        return;
+    }
+    if !policy.allows(&room_member.sender) {
+        info!(
+            "Ignoring invite to {} from {}, who isn't allowed to invite",
+            room.room_id(),
+            room_member.sender
+        );
+        return;
+    }
+
+    tokio::spawn(async move {
+        info!(
+            "Joining {} after an invite from {}",
+            room.room_id(),
+            room_member.sender
+        );
+        // Joining can fail if the homeserver hasn't finished processing the
+        // invite yet, so retry with backoff.
+        let mut delay = Duration::from_secs(2);
+        while let Err(err) = room.join().await {
+            warn!(
+                "Failed to join {}, retrying in {}s: {err}",
+                room.room_id(),
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            if delay > MAX_JOIN_DELAY {
+                warn!("Giving up joining {}: {err}", room.room_id());
+                return;
+            }
+        }
+        info!("Joined {}", room.room_id());
+    });
+}
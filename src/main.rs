@@ -1,8 +1,21 @@
+mod build_info;
+mod conditional;
+mod error;
+mod handlers;
+mod media;
+mod prefetch;
+mod rate_limit;
+mod readiness;
 mod room_list;
+mod room_state;
 mod room_to_html;
 mod timeline;
 
-use std::path::{Path, PathBuf};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{extract, http, response::IntoResponse, routing::get};
 use base64::{Engine, prelude::BASE64_STANDARD};
@@ -12,35 +25,50 @@ use futures::{StreamExt, prelude::*};
 
 use askama::Template;
 use clap::Parser;
+use error::AppError;
 use matrix_sdk::{
-    Client,
+    Client, Room, RoomDisplayName, RoomState,
     authentication::matrix::MatrixSession,
     config::SyncSettings,
     encryption::Encryption,
-    room::{Messages, MessagesOptions},
+    room::{IncludeRelations, RelationsOptions},
     ruma::{
         RoomAliasId,
-        api::client::{
-            filter::FilterDefinition,
-            uiaa::{AuthData, Password, UserIdentifier},
+        api::{
+            Direction,
+            client::{
+                account::register,
+                error::{ErrorBody, ErrorKind},
+                filter::FilterDefinition,
+                uiaa::{AuthData, Password, UserIdentifier},
+            },
         },
         assign,
+        events::relation::RelationType,
     },
 };
+use prefetch::{Landmark, Position};
 use rand::{Rng, distr::Alphanumeric};
-use room_to_html::RoomTemplate;
+use room_to_html::{
+    Density, EventSort, MessageOrder, RoomTemplate, TimeFormat, TimelineItem, group_timeline,
+    has_order_discrepancy, sort_by_timestamp, undecryptable_count,
+};
 use rpassword::prompt_password;
 use rust_embed::Embed;
 use serde::{Deserialize, Serialize};
-use timeline::build_timeline_event;
+use timeline::{TimelineOptions, aggregate_reactions, build_timeline_event, dedup_events};
 use tokio::{fs, signal};
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_log::AsTrace;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use ruma::OwnedRoomId;
+use ruma::{
+    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedRoomOrAliasId,
+    OwnedUserId, RoomId, UserId, events::room::member::MembershipState,
+};
 
-use crate::room_list::room_to_list_entry;
+use crate::room_list::{RoomSort, room_to_list_entry};
 
 #[derive(Embed)]
 #[folder = "dist"]
@@ -86,18 +114,40 @@ pub struct Config {
     #[clap(flatten)]
     pub account_config: AccountConfig,
 
+    #[clap(flatten)]
+    pub render_config: RenderConfig,
+
+    #[clap(flatten)]
+    pub server_config: ServerConfig,
+
     #[clap(flatten)]
     pub(crate) verbose: clap_verbosity_flag::Verbosity,
 }
 
+impl Config {
+    /// The options from the configuration that affect every page.
+    pub fn appearance(&self) -> room_to_html::Appearance {
+        room_to_html::Appearance {
+            custom_css: self.server_config.custom_css.is_some(),
+            emoji_font: self.server_config.emoji_font.is_some(),
+            max_reactions: self.render_config.max_reactions,
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct AccountConfig {
     /// URL of the homeserver to connect to
     #[arg(short, long, env = "MATRIX_SERVER")]
     pub server: String,
     /// Username of the bot
-    #[arg(short, long, env = "MATRIX_USERNAME")]
-    pub username: String,
+    #[arg(
+        short,
+        long,
+        env = "MATRIX_USERNAME",
+        required_unless_present = "guest"
+    )]
+    pub username: Option<String>,
     /// Password of the bot
     #[arg(short, long, env = "MATRIX_PASSWORD")]
     pub password: Option<String>,
@@ -110,14 +160,522 @@ pub struct AccountConfig {
     /// Set the device name, even if it already exists
     #[arg(long, default_value_t = false)]
     pub set_device_name: bool,
+    /// User agent to identify this instance to the homeserver with
+    #[arg(long, default_value = build_info::USER_AGENT, env = "LIBRETTO_USER_AGENT")]
+    pub user_agent: String,
+    /// HTTP, HTTPS or SOCKS5 proxy to send homeserver requests through, such
+    /// as `socks5h://127.0.0.1:9050` for Tor
+    #[arg(long, env = "LIBRETTO_PROXY")]
+    pub proxy: Option<String>,
 
     /// Account recovery key
     #[arg(short, long, env = "MATRIX_ACCOUNT_RECOVERY_KEY")]
     pub recovery_key: Option<String>,
+    /// Never prompt for secrets on the terminal. Missing secrets are an error
+    /// instead, so they must be provided through arguments or the environment
+    #[arg(long, default_value_t = false, env = "LIBRETTO_NON_INTERACTIVE")]
+    pub non_interactive: bool,
+    /// Continue with an unverified device instead of asking for a recovery
+    /// key. Messages the device has no keys for will be unable to be decrypted
+    #[arg(long, default_value_t = false, env = "LIBRETTO_NO_VERIFY")]
+    pub no_verify: bool,
+    /// Set up cross-signing for the account if it hasn't been already, which
+    /// verifies this device
+    #[arg(
+        long,
+        default_value_t = false,
+        env = "LIBRETTO_BOOTSTRAP_CROSS_SIGNING"
+    )]
+    pub bootstrap_cross_signing: bool,
 
     /// Account data directory
     #[arg(short, long, env = "MATRIX_ACCOUNT_DATA_DIR")]
     pub data_dir: Option<PathBuf>,
+
+    /// File to persist the login session and sync token in, which holds the
+    /// access token. Defaults to `session` in the data directory
+    #[arg(long, env = "LIBRETTO_SESSION_FILE")]
+    pub session_file: Option<PathBuf>,
+    /// Keep the client's state and crypto store in memory instead of on disk.
+    /// Nothing is persisted, so every run logs in as a new device
+    #[arg(long, default_value_t = false, env = "LIBRETTO_IN_MEMORY")]
+    pub in_memory: bool,
+    /// Serve a previously synced archive from the local store without
+    /// contacting the homeserver. Only locally cached history is shown
+    #[arg(
+        long,
+        default_value_t = false,
+        env = "LIBRETTO_OFFLINE",
+        conflicts_with_all = ["in_memory", "guest"]
+    )]
+    pub offline: bool,
+    /// Register a guest account instead of logging in, to read public rooms
+    /// without a bot account. Nothing is persisted, and only rooms that allow
+    /// guests to join can be read
+    #[arg(long, default_value_t = false, env = "LIBRETTO_GUEST")]
+    pub guest: bool,
+    /// Rooms for the guest account to join on startup
+    #[arg(
+        long = "guest-room",
+        env = "LIBRETTO_GUEST_ROOMS",
+        value_delimiter = ','
+    )]
+    pub guest_rooms: Vec<OwnedRoomOrAliasId>,
+    /// Accept invites to rooms automatically from the inviters allowed by
+    /// `--autojoin-from`, so rooms can be archived by inviting the account to
+    /// them
+    #[arg(long, default_value_t = false, env = "LIBRETTO_AUTOJOIN")]
+    pub autojoin: bool,
+    /// Accept invites from these user IDs, or from any user on these servers.
+    /// `*` accepts invites from anyone. No invites are accepted if empty
+    #[arg(
+        long = "autojoin-from",
+        env = "LIBRETTO_AUTOJOIN_FROM",
+        value_delimiter = ',',
+        requires = "autojoin"
+    )]
+    pub autojoin_from: Vec<String>,
+}
+
+impl AccountConfig {
+    /// A client builder for `homeserver` with the configured user agent and
+    /// proxy.
+    fn client_builder(&self, homeserver: &str) -> matrix_sdk::ClientBuilder {
+        let builder = Client::builder()
+            .homeserver_url(homeserver)
+            .user_agent(&self.user_agent);
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy),
+            None => builder,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct RenderConfig {
+    /// Maximum gap in minutes between consecutive messages from the same
+    /// sender for them to be grouped together. 0 disables grouping
+    #[arg(long, default_value_t = 5, env = "LIBRETTO_GROUP_WINDOW")]
+    pub group_window: u64,
+
+    /// Number of timeline events to build concurrently when rendering a room
+    #[arg(long, default_value_t = 8, env = "LIBRETTO_TIMELINE_CONCURRENCY")]
+    pub timeline_concurrency: usize,
+
+    /// How many levels of replied-to events to fetch for reply previews. 0
+    /// disables fetching, showing only a link to the replied-to event
+    #[arg(long, default_value_t = 1, env = "LIBRETTO_REPLY_DEPTH")]
+    pub reply_depth: usize,
+
+    /// Locale used to format timestamps
+    #[arg(long, default_value_t = String::from("en-GB"), env = "LIBRETTO_LOCALE")]
+    pub locale: String,
+
+    /// IANA time zone used to format timestamps
+    #[arg(long, default_value_t = String::from("UTC"), env = "LIBRETTO_TIMEZONE")]
+    pub timezone: String,
+
+    /// Append the time zone's abbreviation to timestamps, such as "14:03
+    /// UTC", so they are unambiguous
+    #[arg(long, default_value_t = false, env = "LIBRETTO_SHOW_TIME_ZONE")]
+    pub show_time_zone: bool,
+
+    /// Format timestamps using the viewer's `Accept-Language` header and `tz`
+    /// query parameter, falling back to the configured locale and time zone
+    #[arg(long, default_value_t = false, env = "LIBRETTO_PER_VIEWER_TIME_FORMAT")]
+    pub per_viewer_time_format: bool,
+
+    /// Default order to display messages in. Can be overridden with the
+    /// `order` query parameter
+    #[arg(long, value_enum, default_value_t = MessageOrder::Asc, env = "LIBRETTO_MESSAGE_ORDER")]
+    pub message_order: MessageOrder,
+
+    /// How tightly the timeline is laid out. Can be overridden with the
+    /// `density` query parameter
+    #[arg(long, value_enum, default_value_t = Density::Comfortable, env = "LIBRETTO_DENSITY")]
+    pub density: Density,
+
+    /// The widest the timeline is laid out, in `em`. Can be overridden with
+    /// the `width` query parameter
+    #[arg(
+        long,
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u16).range(CONTENT_WIDTH_RANGE),
+        env = "LIBRETTO_CONTENT_WIDTH"
+    )]
+    pub content_width: u16,
+
+    /// Order events by the homeserver's stream order, or by the timestamps
+    /// they were sent with, which can disagree because of federation delays
+    #[arg(long, value_enum, default_value_t = EventSort::Stream, env = "LIBRETTO_EVENT_SORT")]
+    pub event_sort: EventSort,
+
+    /// Hide text messages with an empty body, instead of showing a
+    /// placeholder
+    #[arg(long, default_value_t = false, env = "LIBRETTO_HIDE_EMPTY_MESSAGES")]
+    pub hide_empty_messages: bool,
+
+    /// Hide `m.notice` messages, which are usually sent by bots
+    #[arg(long, default_value_t = false, env = "LIBRETTO_HIDE_NOTICES")]
+    pub hide_notices: bool,
+
+    /// When none of the messages on a page can be decrypted, show a single
+    /// notice with the messages collapsed behind it, instead of a
+    /// placeholder for each one
+    #[arg(long, default_value_t = false, env = "LIBRETTO_COLLAPSE_UNDECRYPTABLE")]
+    pub collapse_undecryptable: bool,
+
+    /// In encrypted rooms, only load media from events that weren't sent
+    /// from a verified device when it is clicked, rather than with the page
+    #[arg(long, default_value_t = false, env = "LIBRETTO_GATE_UNVERIFIED_MEDIA")]
+    pub gate_unverified_media: bool,
+
+    /// Number of distinct reactions to show on a message before collapsing
+    /// the rest behind a "+N more" expander. 0 shows them all
+    #[arg(long, default_value_t = 8, env = "LIBRETTO_MAX_REACTIONS")]
+    pub max_reactions: usize,
+
+    /// Show the original authors of messages relayed by bridges, recognised
+    /// from per-message profiles, and from `<name>` or `[name]` prefixes on
+    /// messages sent by a `--bridge-bot`
+    #[arg(long, default_value_t = false, env = "LIBRETTO_BRIDGE_ATTRIBUTION")]
+    pub bridge_attribution: bool,
+
+    /// Show events that are normally folded into other events, such as
+    /// reactions and redactions, for debugging
+    #[arg(long, default_value_t = false, env = "LIBRETTO_SHOW_HIDDEN_EVENTS")]
+    pub show_hidden_events: bool,
+
+    /// Number of earlier pages of history to fetch in the background when a
+    /// room is viewed, so loading earlier messages is instant. At most 10
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(..=10),
+        env = "LIBRETTO_PREFETCH_PAGES"
+    )]
+    pub prefetch_pages: u8,
+
+    /// Maximum number of recent messages per room to show on a user's page
+    #[arg(long, default_value_t = 20, env = "LIBRETTO_USER_PAGE_LIMIT")]
+    pub user_page_limit: usize,
+
+    /// Tag messages with the homeserver they were sent from
+    #[arg(long, default_value_t = false, env = "LIBRETTO_SHOW_ORIGIN_SERVER")]
+    pub show_origin_server: bool,
+
+    /// Default order of the room list. Can be overridden with the `sort`
+    /// query parameter
+    #[arg(long, value_enum, default_value_t = RoomSort::Name, env = "LIBRETTO_ROOM_SORT")]
+    pub room_sort: RoomSort,
+
+    /// Bot accounts whose direct rooms are hidden from the room list. Rooms
+    /// with at most two members where one is a listed bot are hidden
+    #[arg(long = "hide-bot", env = "LIBRETTO_HIDE_BOTS", value_delimiter = ',')]
+    pub hide_bots: Vec<OwnedUserId>,
+
+    /// Users whose intentional mentions are highlighted in timelines, in
+    /// addition to mentions of this account and of the whole room
+    #[arg(
+        long = "highlight-mention",
+        env = "LIBRETTO_HIGHLIGHT_MENTIONS",
+        value_delimiter = ','
+    )]
+    pub highlight_mentions: Vec<OwnedUserId>,
+
+    /// Relay bots whose messages start with the original author's name, as
+    /// `<name> message` or `[name] message`. Only their messages have the
+    /// prefix read as an author with `--bridge-attribution`
+    #[arg(
+        long = "bridge-bot",
+        env = "LIBRETTO_BRIDGE_BOTS",
+        value_delimiter = ','
+    )]
+    pub bridge_bots: Vec<OwnedUserId>,
+
+    /// Hide rooms from the room list if their canonical or alternative
+    /// aliases match this regular expression
+    #[arg(long, env = "LIBRETTO_HIDE_ROOM_ALIASES")]
+    pub hide_room_aliases: Option<regex::Regex>,
+
+    /// Show a sparkline of each room's activity over this many days in the
+    /// room list, counted from cached events. 0 disables it
+    #[arg(long, default_value_t = 0, env = "LIBRETTO_ACTIVITY_DAYS")]
+    pub activity_days: u16,
+
+    /// Rooms with more joined members than this are named by their name or
+    /// alias in the room list, or their ID, rather than a name computed from
+    /// their members, which is slow for huge rooms. 0 always computes names
+    #[arg(long, default_value_t = 10000, env = "LIBRETTO_LARGE_ROOM_MEMBERS")]
+    pub large_room_members: u64,
+
+    /// Size in pixels of the room avatar thumbnails requested for the room
+    /// list
+    #[arg(long, default_value_t = 96, env = "LIBRETTO_LIST_AVATAR_SIZE")]
+    pub list_avatar_size: u32,
+
+    /// Size in pixels of the room avatar thumbnail requested for the header
+    /// of a room page
+    #[arg(long, default_value_t = 128, env = "LIBRETTO_HEADER_AVATAR_SIZE")]
+    pub header_avatar_size: u32,
+}
+
+impl RenderConfig {
+    pub fn group_window(&self) -> Duration {
+        Duration::from_secs(self.group_window * 60)
+    }
+
+    pub fn timeline_options(&self, fetch_timeout: Duration, offline: bool) -> TimelineOptions {
+        TimelineOptions {
+            offline,
+            reply_depth: self.reply_depth,
+            fetch_timeout,
+            bridge_attribution: self.bridge_attribution,
+            bridge_bots: self.bridge_bots.clone(),
+            show_origin_server: self.show_origin_server,
+            highlight_users: self.highlight_mentions.clone(),
+            gate_unverified_media: self.gate_unverified_media,
+        }
+    }
+
+    /// Whether an event should be shown in the rendered timeline.
+    ///
+    /// This is the only place events are filtered out of the timeline, after
+    /// hidden events like reactions have been folded into the events they
+    /// relate to.
+    pub fn is_visible(&self, event: &timeline::TimelineEvent) -> bool {
+        // Only events confirmed by the homeserver belong in the archive.
+        if event.is_local_echo() {
+            debug!(
+                "Skipping local echo from {} at {}",
+                event.sender,
+                event.timestamp.get()
+            );
+            return false;
+        }
+        if event.is_hidden() {
+            return self.show_hidden_events;
+        }
+        !(self.hide_empty_messages && event.is_empty_message())
+            && !(self.hide_notices && event.is_notice())
+    }
+}
+
+/// How often the event cache is checked against its clear threshold.
+const EVENT_CACHE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Parser, Debug)]
+pub struct ServerConfig {
+    /// Key used to sign media proxy URLs, at least 16 bytes long. If not
+    /// set, a random key is generated and stored in the data directory
+    #[arg(long, env = "LIBRETTO_MEDIA_SIGNING_KEY")]
+    pub media_signing_key: Option<String>,
+
+    /// Seconds to wait for the homeserver when fetching events or media
+    /// before giving up
+    #[arg(long, default_value_t = 30, env = "LIBRETTO_UPSTREAM_TIMEOUT")]
+    pub upstream_timeout: u64,
+
+    /// Number of cached events above which a room's whole event cache is
+    /// cleared, checked every five minutes. Cleared rooms render slower until
+    /// their history is fetched from the homeserver again, so this trades
+    /// render latency for memory. Not applied offline. 0 never clears the
+    /// cache (the default)
+    #[arg(
+        long,
+        default_value_t = 0,
+        env = "LIBRETTO_EVENT_CACHE_CLEAR_THRESHOLD"
+    )]
+    pub event_cache_clear_threshold: usize,
+
+    /// Maximum number of media fetches the media proxy makes at once. Further
+    /// requests wait until one finishes
+    #[arg(
+        long,
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u16).range(1..),
+        env = "LIBRETTO_MEDIA_CONCURRENCY"
+    )]
+    pub media_concurrency: u16,
+
+    /// Show the details of errors on error pages, which can include internal
+    /// paths and homeserver URLs. Errors are always logged in full
+    #[arg(long, default_value_t = false, env = "LIBRETTO_VERBOSE_ERRORS")]
+    pub verbose_errors: bool,
+
+    /// CSS file to serve after the default stylesheets on every page, to
+    /// override the theme
+    #[arg(long, env = "LIBRETTO_CUSTOM_CSS")]
+    pub custom_css: Option<PathBuf>,
+
+    /// Font file (WOFF2, WOFF, TTF or OTF) to render emoji with, so they look
+    /// the same whatever the viewer's platform. Emoji use the viewer's system
+    /// font by default
+    #[arg(long, env = "LIBRETTO_EMOJI_FONT")]
+    pub emoji_font: Option<PathBuf>,
+
+    /// Also write logs to this file, rotated as set by `--log-rotation`.
+    /// Rotated files get the date appended to the name
+    #[arg(long, env = "LIBRETTO_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// How often to start a new log file
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily, env = "LIBRETTO_LOG_ROTATION")]
+    pub log_rotation: LogRotation,
+
+    /// Number of rotated log files to keep. 0 keeps them all
+    #[arg(long, default_value_t = 7, env = "LIBRETTO_LOG_MAX_FILES")]
+    pub log_max_files: usize,
+
+    /// Only write logs to the log file, not to stdout
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "log_file",
+        env = "LIBRETTO_LOG_FILE_ONLY"
+    )]
+    pub log_file_only: bool,
+
+    /// Maximum requests per minute from each client IP address, including
+    /// media requests. Clients over the limit get a 429 response. Disabled by
+    /// default
+    #[arg(long, env = "LIBRETTO_RATE_LIMIT")]
+    pub rate_limit: Option<std::num::NonZeroU32>,
+
+    /// Which homeserver endpoints the media proxy fetches media from. `auto`
+    /// picks authenticated media if the homeserver's advertised versions
+    /// support it
+    #[arg(long, value_enum, default_value_t = media::MediaEndpoint::Auto, env = "LIBRETTO_MEDIA_ENDPOINT")]
+    pub media_endpoint: media::MediaEndpoint,
+
+    /// Where the latest page of a room comes from. `cache-first` uses events
+    /// already received through sync when there are any, which saves a
+    /// request to the homeserver for recently active rooms
+    #[arg(long, value_enum, default_value_t = prefetch::FetchStrategy::Messages, env = "LIBRETTO_FETCH_STRATEGY")]
+    pub fetch_strategy: prefetch::FetchStrategy,
+
+    /// Number of rooms to look up concurrently when building the room list
+    /// or a user's page
+    #[arg(
+        long,
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u16).range(1..),
+        env = "LIBRETTO_ROOM_LIST_CONCURRENCY"
+    )]
+    pub room_list_concurrency: u16,
+
+    /// Seconds to reuse a room's entry in the room list for, unless sync
+    /// brings changes to the room first. 0 looks every room up on each load
+    #[arg(long, default_value_t = 3600, env = "LIBRETTO_ROOM_LIST_CACHE_TTL")]
+    pub room_list_cache_ttl: u64,
+
+    /// The most pages of history walked back through to find the start of a
+    /// room or a date to jump to, and of replies fetched for one page of a
+    /// thread. Searches that reach it go to the earliest page reached
+    #[arg(
+        long,
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u16).range(1..),
+        env = "LIBRETTO_HISTORY_SEARCH_PAGES"
+    )]
+    pub history_search_pages: u16,
+
+    /// The most pages of history searched for media for one page of a room's
+    /// media gallery
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u16).range(1..),
+        env = "LIBRETTO_GALLERY_SEARCH_PAGES"
+    )]
+    pub gallery_search_pages: u16,
+
+    /// The URL the archive is served at, such as
+    /// `https://archive.example.org`, for the absolute links on printed
+    /// pages. Printed links are relative if not set
+    #[arg(long, env = "LIBRETTO_PUBLIC_URL")]
+    pub public_url: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_timeout)
+    }
+
+    /// A writer for the configured log file, if there is one.
+    fn log_file_appender(&self) -> eyre::Result<Option<RollingFileAppender>> {
+        let Some(log_file) = &self.log_file else {
+            return Ok(None);
+        };
+        let file_name = log_file
+            .file_name()
+            .wrap_err_with(|| format!("Log file {} has no file name", log_file.display()))?;
+        let directory = log_file
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let mut builder = RollingFileAppender::builder()
+            .rotation(self.log_rotation.into())
+            .filename_prefix(file_name.to_string_lossy());
+        if self.log_max_files > 0 {
+            builder = builder.max_log_files(self.log_max_files);
+        }
+        let appender = builder
+            .build(directory)
+            .wrap_err_with(|| format!("Failed to open log file {}", log_file.display()))?;
+        Ok(Some(appender))
+    }
+}
+
+/// How often a new log file is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    /// Always write to the same file
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// State shared between the HTTP handlers.
+#[derive(Clone)]
+struct AppState {
+    client: Client,
+    config: Arc<Config>,
+    time_format: TimeFormat,
+    media: media::MediaRegistry,
+    pages: prefetch::PageCache,
+    room_list_cache: room_list::RoomListCache,
+    sync_progress: Arc<readiness::SyncProgress>,
+}
+
+/// Query parameters accepted by the room page.
+#[derive(Debug, Default, Deserialize)]
+struct RoomQuery {
+    /// IANA time zone to display timestamps in.
+    tz: Option<String>,
+    /// The order to display messages in.
+    order: Option<MessageOrder>,
+    /// Only show messages intentionally mentioning this user.
+    mentions: Option<OwnedUserId>,
+    /// Only show messages of these comma-separated types, such as
+    /// `image,file`.
+    types: Option<String>,
+    /// Overrides the configured timeline density.
+    density: Option<Density>,
+    /// Overrides the configured content width, in `em`.
+    width: Option<u16>,
 }
 
 /// The data needed to re-build a client.
@@ -160,38 +718,162 @@ async fn main() -> eyre::Result<()> {
     let filter = tracing_subscriber::EnvFilter::builder()
         .with_default_directive(config.verbose.log_level_filter().as_trace().into())
         .from_env_lossy();
+    let log_file = config.server_config.log_file_appender()?;
+    let stdout_layer = (!config.server_config.log_file_only || log_file.is_none())
+        .then(tracing_subscriber::fmt::layer);
+    let file_layer = log_file.map(|appender| {
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(appender)
+    });
     tracing_subscriber::registry()
         .with(filter)
-        .with(tracing_subscriber::fmt::layer())
+        .with(stdout_layer)
+        .with(file_layer)
         .init();
 
     info!("Starting up");
+    if let Some(custom_css) = &config.server_config.custom_css {
+        if !custom_css.is_file() {
+            warn!(
+                "Custom CSS file {} doesn't exist, so it won't be applied until it does",
+                custom_css.display()
+            );
+        }
+    }
+    if let Some(emoji_font) = &config.server_config.emoji_font {
+        if font_content_type(emoji_font).is_none() {
+            return Err(eyre::eyre!(
+                "Emoji font {} isn't a WOFF2, WOFF, TTF or OTF file",
+                emoji_font.display()
+            ));
+        }
+        if !emoji_font.is_file() {
+            warn!(
+                "Emoji font {} doesn't exist, so system emoji will be shown until it does",
+                emoji_font.display()
+            );
+        }
+    }
+
+    let time_format = TimeFormat::new(
+        &config.render_config.locale,
+        &config.render_config.timezone,
+        config.render_config.show_time_zone,
+    )?;
 
     let data_dir = config.account_config.data_dir.clone().unwrap_or_else(|| {
         dirs::data_dir()
             .expect("no data_dir directory found")
             .join("libretto")
     });
-    let session_file = data_dir.join("session");
+    // In-memory and guest sessions can't be restored, so there is no session
+    // file.
+    let is_ephemeral = config.account_config.in_memory || config.account_config.guest;
+    let session_file = (!is_ephemeral).then(|| {
+        config
+            .account_config
+            .session_file
+            .clone()
+            .unwrap_or_else(|| data_dir.join("session"))
+    });
 
-    let (client, sync_token) = if session_file.exists() {
-        restore_session(&session_file).await?
-    } else {
-        (
-            login(&data_dir, &session_file, &config.account_config).await?,
+    let offline = config.account_config.offline;
+    if offline && !session_file.as_ref().is_some_and(|file| file.exists()) {
+        eyre::bail!("Offline mode needs a session from a previous online run");
+    }
+
+    let (client, sync_token) = match &session_file {
+        _ if config.account_config.guest => (guest_login(&config.account_config).await?, None),
+        Some(session_file) if session_file.exists() => {
+            restore_session(session_file, &config.account_config).await?
+        }
+        _ => (
+            login(&data_dir, session_file.as_deref(), &config.account_config).await?,
             None,
-        )
+        ),
     };
 
     client.event_cache().subscribe()?;
+    // Offline, the event cache is all the history there is.
+    if config.server_config.event_cache_clear_threshold > 0 && !offline {
+        tokio::spawn(clear_large_event_caches(
+            client.clone(),
+            config.server_config.event_cache_clear_threshold,
+        ));
+    }
 
-    run(&client, sync_token, &session_file, &config).await?;
+    let media_signing_key = media_signing_key(
+        &config.server_config,
+        (!is_ephemeral).then_some(data_dir.as_path()),
+    )
+    .await?;
 
-    let app = axum::Router::new()
+    let media = media::MediaRegistry::new(
+        &media_signing_key,
+        config.server_config.media_concurrency.into(),
+    );
+
+    let rate_limit = config.server_config.rate_limit;
+    let config = Arc::new(config);
+    let sync_progress = Arc::new(readiness::SyncProgress::default());
+    let pages = prefetch::PageCache::new(offline, config.server_config.fetch_strategy);
+    let room_list_cache = room_list::RoomListCache::new(Duration::from_secs(
+        config.server_config.room_list_cache_ttl,
+    ));
+
+    // `get` routes also answer HEAD requests, with the same headers as GET
+    // and the body stripped, so proxies and link checkers don't see a 405.
+    let mut app = axum::Router::new()
         .route("/room/{room_id}", get(room))
+        .route("/room/{room_id}/before/{token}", get(room_before))
+        .route("/room/{room_id}/after/{token}", get(room_after))
+        .route("/room/{room_id}/start", get(room_start))
+        .route("/room/{room_id}/at-date", get(room_at_date_form))
+        .route("/room/{room_id}/at-date/{date}", get(room_at_date))
+        .route("/room/{room_id}/state", get(room_state))
+        .route("/room/{room_id}/thread/{thread_root}", get(thread))
+        .route(
+            "/room/{room_id}/thread/{thread_root}/after/{token}",
+            get(thread_after),
+        )
+        .route("/room/{room_id}/media", get(media_gallery))
+        .route("/user/{user_id}", get(user))
         .route("/", get(index))
+        .route("/api/rooms", get(api_rooms))
+        .route("/api/discover", get(api_discover))
+        .route("/media/{server_name}/{media_id}", get(media::media))
+        // Pages are only served once the initial sync is done, but the
+        // routes below stay available to report on startup.
+        .route_layer(axum::middleware::from_fn_with_state(
+            sync_progress.clone(),
+            readiness::require_synced,
+        ))
+        .route("/version", get(version))
+        .route("/ready", get(readiness::ready))
+        .route("/custom.css", get(custom_css))
+        .route("/emoji-font", get(emoji_font))
         .fallback(get(static_service::<Dist>))
-        .with_state(client.clone());
+        .with_state(AppState {
+            client: client.clone(),
+            config: config.clone(),
+            time_format,
+            media,
+            pages: pages.clone(),
+            room_list_cache: room_list_cache.clone(),
+            sync_progress: sync_progress.clone(),
+        });
+    // Outside the routes, so errors from any of them get the full page.
+    app = app.layer(axum::middleware::from_fn_with_state(
+        config.clone(),
+        error::render_error_pages,
+    ));
+    if let Some(requests_per_minute) = rate_limit {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            rate_limit::RateLimiter::new(requests_per_minute),
+            rate_limit::limit,
+        ));
+    }
 
     // try to first get a socket from listenfd, if that does not give us
     // one (eg: no systemd or systemfd), open on port 3000 instead.
@@ -204,6 +886,33 @@ async fn main() -> eyre::Result<()> {
         None => tokio::net::TcpListener::bind("0.0.0.0:3000").await,
     }?;
 
+    // Serve during the initial sync, so its progress can be checked.
+    info!(listener = ?listener,  "Serving!");
+    let server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .into_future(),
+    );
+
+    if offline {
+        info!("Running offline, serving only locally stored history");
+        sync_progress.finish(client.rooms().len());
+        server.await??;
+        return Ok(());
+    }
+
+    run(
+        &client,
+        sync_token,
+        session_file.as_deref(),
+        &config,
+        &sync_progress,
+    )
+    .await?;
+
     let signal = shutdown_signal();
 
     let sync_task = tokio::spawn(async move {
@@ -212,10 +921,30 @@ async fn main() -> eyre::Result<()> {
             client.sync_with_result_callback(SyncSettings::default(), |sync_result| async {
                 let response = sync_result?;
 
+                let rooms = &response.rooms;
+                for (room_id, update) in &rooms.join {
+                    pages.record_sync(
+                        room_id,
+                        &update.timeline.events,
+                        update.timeline.prev_batch.as_deref(),
+                        update.timeline.limited,
+                    );
+                }
+                room_list_cache.invalidate(
+                    rooms
+                        .join
+                        .keys()
+                        .chain(rooms.leave.keys())
+                        .chain(rooms.invite.keys()),
+                );
+                room_list_cache.invalidate_spaces(rooms);
+
                 // We persist the token each time to be able to restore our session
-                persist_sync_token(&session_file, response.next_batch)
-                    .await
-                    .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
+                if let Some(session_file) = &session_file {
+                    persist_sync_token(session_file, response.next_batch)
+                        .await
+                        .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
+                }
 
                 Ok(matrix_sdk::LoopCtrl::Continue)
             });
@@ -226,10 +955,7 @@ async fn main() -> eyre::Result<()> {
         }
     });
 
-    info!(listener = ?listener,  "Serving!");
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    server.await??;
     sync_task.await?;
     Ok(())
 }
@@ -270,100 +996,1335 @@ async fn shutdown_signal() {
     }
 }
 
-async fn index(
-    extract::State(client): extract::State<Client>,
-) -> Result<impl axum::response::IntoResponse, AppError> {
+/// Query parameters accepted by the room list.
+#[derive(Debug, Default, Deserialize)]
+struct IndexQuery {
+    /// Overrides the configured room sort.
+    sort: Option<RoomSort>,
+}
+
+/// The joined rooms shown in the room list, leaving out hidden rooms, in the
+/// given order.
+async fn visible_rooms(
+    client: &Client,
+    config: &Config,
+    media: &media::MediaRegistry,
+    cache: &room_list::RoomListCache,
+    sort: RoomSort,
+) -> room_list::RoomList {
     let mut list = room_list::RoomList::new();
-    for room in client.joined_rooms() {
-        if let Ok(room_entry) = room_to_list_entry(&room).await {
-            list.add_room(room_entry);
-        }
+    let mut space_memberships = cache.space_memberships(client).await;
+    let entries = stream::iter(client.joined_rooms())
+        .map(|room| async move { list_entry(&room, config, media, cache).await })
+        .buffered(config.server_config.room_list_concurrency.into())
+        .collect::<Vec<_>>()
+        .await;
+    // Spaces are cached apart from the entries, as changes to a space don't
+    // invalidate the entries of the rooms in it.
+    for mut room_entry in entries.into_iter().flatten() {
+        room_entry.spaces = space_memberships.remove(&room_entry.id).unwrap_or_default();
+        list.add_room(room_entry);
+    }
+    list.sort(sort);
+    list
+}
+
+/// A room's entry in the room list, or `None` if it's hidden, using the
+/// cached entry if there is one.
+async fn list_entry(
+    room: &Room,
+    config: &Config,
+    media: &media::MediaRegistry,
+    cache: &room_list::RoomListCache,
+) -> Option<room_list::RoomListEntry> {
+    if let Some(entry) = cache.get(room.room_id()) {
+        return entry;
     }
+    let render_config = &config.render_config;
+    let entry = if room_list::is_utility_room(
+        room,
+        &render_config.hide_bots,
+        render_config.hide_room_aliases.as_ref(),
+    )
+    .await
+    {
+        None
+    } else {
+        // Rooms that fail to load are left out without being cached, so
+        // they're tried again on the next load.
+        Some(room_to_list_entry(room, media, render_config).await.ok()?)
+    };
+    cache.insert(room.room_id().to_owned(), entry.clone());
+    entry
+}
 
-    list.sort_by_display_names();
+/// Whether a request's `Accept` header asks for JSON rather than HTML.
+fn wants_json(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+}
 
-    let template = room_to_html::RoomListTemplate { rooms: list.rooms };
+async fn index(
+    extract::State(AppState {
+        client,
+        config,
+        media,
+        room_list_cache,
+        ..
+    }): extract::State<AppState>,
+    extract::Query(query): extract::Query<IndexQuery>,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let sort = query.sort.unwrap_or(config.render_config.room_sort);
+    let list = visible_rooms(&client, &config, &media, &room_list_cache, sort).await;
+    if wants_json(&headers) {
+        return Ok(axum::Json(list).into_response());
+    }
+
+    let template = room_to_html::RoomListTemplate {
+        appearance: config.appearance(),
+        all_hidden: list.rooms.is_empty() && !client.joined_rooms().is_empty(),
+        rooms: list.rooms,
+        user_id: client.user_id().map(ToOwned::to_owned),
+    };
 
     Ok(axum::response::Html(template.render()?).into_response())
 }
 
+/// The room list as JSON, with the same rooms hidden as on the HTML index.
+async fn api_rooms(
+    extract::State(AppState {
+        client,
+        config,
+        media,
+        room_list_cache,
+        ..
+    }): extract::State<AppState>,
+    extract::Query(query): extract::Query<IndexQuery>,
+) -> axum::Json<room_list::RoomList> {
+    let sort = query.sort.unwrap_or(config.render_config.room_sort);
+    axum::Json(visible_rooms(&client, &config, &media, &room_list_cache, sort).await)
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscoverQuery {
+    alias: OwnedRoomAliasId,
+}
+
+/// Where a room is archived on this instance, returned by [`api_discover`].
+#[derive(Debug, Serialize)]
+struct Discovery {
+    room_id: OwnedRoomId,
+    canonical_alias: Option<OwnedRoomAliasId>,
+    /// The path of the room's page, relative to this instance.
+    url: String,
+}
+
+/// Look up where a room is archived on this instance by one of its aliases,
+/// so other archives and directories can link to it.
+///
+/// Only rooms shown in the room list can be discovered. Others are reported
+/// as not found, the same as unknown aliases.
+async fn api_discover(
+    extract::State(AppState { client, config, .. }): extract::State<AppState>,
+    extract::Query(DiscoverQuery { alias }): extract::Query<DiscoverQuery>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    let room_id = resolve_room_id(
+        &client,
+        alias.as_str(),
+        timeout,
+        config.account_config.offline,
+    )
+    .await?;
+    let not_found = || AppError::NotFound(eyre::eyre!("Room alias {alias} isn't archived here"));
+    let room = client
+        .get_room(&room_id)
+        .filter(|room| room.state() == RoomState::Joined)
+        .ok_or_else(not_found)?;
+    let render_config = &config.render_config;
+    if room_list::is_utility_room(
+        &room,
+        &render_config.hide_bots,
+        render_config.hide_room_aliases.as_ref(),
+    )
+    .await
+    {
+        return Err(not_found());
+    }
+
+    let canonical_alias = room.canonical_alias();
+    let link_id = canonical_alias
+        .as_ref()
+        .map_or(room_id.as_str(), |alias| alias.as_str());
+    Ok(axum::Json(Discovery {
+        url: format!("/room/{}", askama::filters::urlencode_strict(link_id)?),
+        room_id,
+        canonical_alias,
+    }))
+}
+
+/// Serve the operator's custom stylesheet, read on each request so edits
+/// apply without a restart.
+async fn custom_css(
+    extract::State(AppState { config, .. }): extract::State<AppState>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let path = config
+        .server_config
+        .custom_css
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound(eyre::eyre!("No custom CSS is configured")))?;
+    let css = fs::read(path)
+        .await
+        .wrap_err_with(|| format!("Failed to read custom CSS from {}", path.display()))?;
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, "text/css; charset=utf-8"),
+            (http::header::CACHE_CONTROL, "public, max-age=300"),
+        ],
+        css,
+    ))
+}
+
+/// Serve the operator's emoji font, read on each request like the custom
+/// stylesheet.
+async fn emoji_font(
+    extract::State(AppState { config, .. }): extract::State<AppState>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let path = config
+        .server_config
+        .emoji_font
+        .as_ref()
+        .ok_or_else(|| AppError::NotFound(eyre::eyre!("No emoji font is configured")))?;
+    let content_type = font_content_type(path).unwrap_or("application/octet-stream");
+    let font = fs::read(path)
+        .await
+        .wrap_err_with(|| format!("Failed to read emoji font from {}", path.display()))?;
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, content_type),
+            (http::header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        font,
+    ))
+}
+
+/// The content type of a font file, from its extension.
+fn font_content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "woff2" => Some("font/woff2"),
+        "woff" => Some("font/woff"),
+        "ttf" => Some("font/ttf"),
+        "otf" => Some("font/otf"),
+        _ => None,
+    }
+}
+
+async fn version() -> axum::Json<build_info::BuildInfo> {
+    axum::Json(build_info::BUILD_INFO)
+}
+
 async fn room(
-    extract::State(client): extract::State<Client>,
+    extract::State(state): extract::State<AppState>,
+    extract::Path(room_id): extract::Path<String>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    render_room(
+        state,
+        &room_id,
+        None,
+        Direction::Backward,
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// Render the page of a room's history before a pagination token.
+async fn room_before(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((room_id, token)): extract::Path<(String, String)>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    render_room(
+        state,
+        &room_id,
+        Some(token),
+        Direction::Backward,
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// Render the page of a room's history after a pagination token, to read
+/// forward from an earlier page.
+async fn room_after(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((room_id, token)): extract::Path<(String, String)>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    render_room(
+        state,
+        &room_id,
+        Some(token),
+        Direction::Forward,
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// Whether a page of history includes the room's create event, and so is the
+/// start of the room.
+fn has_create_event(events: &[matrix_sdk::deserialized_responses::TimelineEvent]) -> bool {
+    events.iter().any(|event| {
+        event
+            .raw()
+            .get_field::<String>("type")
+            .ok()
+            .flatten()
+            .as_deref()
+            == Some("m.room.create")
+    })
+}
+
+/// Render the earliest page of a room's history, found by paginating back
+/// until the room's create event or the end of the available history.
+///
+/// Rooms with more history than `--history-search-pages` pages show the
+/// earliest page reached. Where the search ended is remembered for a while.
+async fn room_start(
+    extract::State(state): extract::State<AppState>,
+    extract::Path(room_id): extract::Path<String>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = state.config.server_config.upstream_timeout();
+    let resolved_room_id = resolve_room_id(
+        &state.client,
+        &room_id,
+        timeout,
+        state.config.account_config.offline,
+    )
+    .await?;
+    let room = joined_room(&state.client, &resolved_room_id)?;
+
+    let from = match state.pages.landmark(&resolved_room_id, Landmark::Start) {
+        Some(position) => position.token,
+        None => {
+            let mut from = None;
+            for _ in 0..state.config.server_config.history_search_pages {
+                let page = with_timeout(
+                    timeout,
+                    "fetching messages",
+                    state.pages.messages(&room, from.clone()),
+                )
+                .await?;
+                match page.end {
+                    Some(end) if !has_create_event(&page.chunk) => from = Some(end),
+                    _ => break,
+                }
+            }
+            state.pages.record_landmark(
+                &resolved_room_id,
+                Landmark::Start,
+                Position {
+                    token: from.clone(),
+                    event_id: None,
+                },
+            );
+            from
+        }
+    };
+
+    render_room(
+        state,
+        &room_id,
+        from,
+        Direction::Backward,
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// The message types that room pages can be filtered to, without the `m.`
+/// prefix.
+const MESSAGE_TYPES: &[&str] = &[
+    "text", "emote", "notice", "image", "file", "video", "audio", "location",
+];
+
+/// Parse a comma-separated list of message types to filter a page to,
+/// ignoring unknown types.
+fn message_types(types: Option<&str>) -> Vec<&'static str> {
+    let Some(types) = types else {
+        return Vec::new();
+    };
+    MESSAGE_TYPES
+        .iter()
+        .copied()
+        .filter(|known| {
+            types
+                .split(',')
+                .any(|message_type| message_type.trim() == *known)
+        })
+        .collect()
+}
+
+/// The content widths, in `em`, that the timeline can be laid out at.
+const CONTENT_WIDTH_RANGE: std::ops::RangeInclusive<i64> = 20..=200;
+
+/// Redirect to the page of a room's history with the first message on or
+/// after a date, found by paginating back until a message before the date.
+///
+/// The date is midnight in the page's time zone. Dates after the latest
+/// message go to the latest page, and dates further back than
+/// `--history-search-pages` pages go to the earliest page reached. Where the
+/// search ended is remembered for a while.
+async fn room_at_date(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((room_id, date)): extract::Path<(String, String)>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = state.config.server_config.upstream_timeout();
+    let resolved_room_id = resolve_room_id(
+        &state.client,
+        &room_id,
+        timeout,
+        state.config.account_config.offline,
+    )
+    .await?;
+    let room = joined_room(&state.client, &resolved_room_id)?;
+    let date = parse_date(&date)?;
+    let time_format = viewer_time_format(&state.config, state.time_format, &headers, &query);
+    let start_of_day = date
+        .to_zoned(time_format.time_zone)
+        .map_err(|err| AppError::BadRequest(err.into()))?
+        .timestamp()
+        .as_millisecond();
+
+    let landmark = Landmark::Date(start_of_day);
+    let target = match state.pages.landmark(&resolved_room_id, landmark) {
+        Some(position) => position,
+        None => {
+            // The earliest event found at or after the date, and its page.
+            let mut target = Position {
+                token: None,
+                event_id: None,
+            };
+            let mut from = None;
+            for _ in 0..state.config.server_config.history_search_pages {
+                let page = with_timeout(
+                    timeout,
+                    "fetching messages",
+                    state.pages.messages(&room, from.clone()),
+                )
+                .await?;
+                let mut reached_date = false;
+                // Pages are newest first, so the last match is the earliest.
+                for event in &page.chunk {
+                    let Some(timestamp) = event
+                        .raw()
+                        .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                        .ok()
+                        .flatten()
+                    else {
+                        continue;
+                    };
+                    if i64::from(timestamp.0) < start_of_day {
+                        reached_date = true;
+                    } else if let Some(event_id) = event.event_id() {
+                        target = Position {
+                            token: from.clone(),
+                            event_id: Some(event_id),
+                        };
+                    }
+                }
+                match page.end {
+                    Some(end) if !reached_date => from = Some(end),
+                    _ => break,
+                }
+            }
+            state
+                .pages
+                .record_landmark(&resolved_room_id, landmark, target.clone());
+            target
+        }
+    };
+
+    let room_path = format!("/room/{}", askama::filters::urlencode_strict(&room_id)?);
+    let query_suffix = uri
+        .query()
+        .map(|query| format!("?{query}"))
+        .unwrap_or_default();
+    let page_path = match &target.token {
+        Some(token) => format!(
+            "{room_path}/before/{}",
+            askama::filters::urlencode_strict(token)?
+        ),
+        None => room_path,
+    };
+    let location = match &target.event_id {
+        Some(event_id) => format!("{page_path}{query_suffix}#event-{event_id}"),
+        None => format!("{page_path}{query_suffix}"),
+    };
+    Ok(axum::response::Redirect::to(&location))
+}
+
+/// Parse a date in `YYYY-MM-DD` form from a request.
+fn parse_date(date: &str) -> Result<jiff::civil::Date, AppError> {
+    date.parse().map_err(|err: jiff::Error| {
+        AppError::BadRequest(eyre::Report::new(err).wrap_err(format!("Invalid date {date}")))
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct AtDateQuery {
+    date: String,
+}
+
+/// Redirect the jump to date form to [`room_at_date`], as forms can only
+/// put the date in the query string.
+///
+/// The rest of the query, such as the viewer's time zone, is carried over.
+async fn room_at_date_form(
     extract::Path(room_id): extract::Path<String>,
+    extract::Query(AtDateQuery { date }): extract::Query<AtDateQuery>,
+    uri: http::Uri,
 ) -> Result<impl axum::response::IntoResponse, AppError> {
-    let room_id: OwnedRoomId = if let Ok(alias) = <&RoomAliasId>::try_from(room_id.as_str()) {
-        client.resolve_room_alias(alias).await?.room_id
+    let date = parse_date(&date)?;
+    let rest: Vec<_> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty() && param.split('=').next() != Some("date"))
+        .collect();
+    let query_suffix = if rest.is_empty() {
+        String::new()
     } else {
-        OwnedRoomId::try_from(room_id.as_str()).context("Room ID was not a valid ID or alias!")?
+        format!("?{}", rest.join("&"))
     };
+    Ok(axum::response::Redirect::to(&format!(
+        "/room/{}/at-date/{date}{query_suffix}",
+        askama::filters::urlencode_strict(&room_id)?
+    )))
+}
 
-    client
-        .encryption()
-        .backups()
-        .download_room_keys_for_room(&room_id)
-        .await
-        .inspect_err(|e| {
-            error!("Failed to download room keys for room {room_id}: {e}");
-        })?;
+#[derive(Debug, Deserialize)]
+struct StateQuery {
+    /// The event to show the room's state at.
+    at: Option<OwnedEventId>,
+}
+
+/// Render a snapshot of a room's state, either now or at a given event.
+async fn room_state(
+    extract::State(AppState { client, config, .. }): extract::State<AppState>,
+    extract::Path(room_id): extract::Path<String>,
+    extract::Query(StateQuery { at }): extract::Query<StateQuery>,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    let room_id =
+        resolve_room_id(&client, &room_id, timeout, config.account_config.offline).await?;
+    let room = joined_room(&client, &room_id)?;
+
+    if config.account_config.offline && at.is_some() {
+        return Err(AppError::Offline(eyre::eyre!(
+            "The state of {room_id} at an event can't be fetched offline"
+        )));
+    }
+    let historical = match &at {
+        Some(event_id) => room_state::state_at_event(&room, event_id, timeout)
+            .await
+            .inspect_err(|err| {
+                warn!("Showing the current state of {room_id} instead of at {event_id}: {err}");
+            })
+            .ok(),
+        None => None,
+    };
+    let is_historical = historical.is_some();
+    let snapshot = match historical {
+        Some(snapshot) => snapshot,
+        None => room_state::current_state(&room).await?,
+    };
+
+    let template = room_to_html::RoomStateTemplate {
+        appearance: config.appearance(),
+        room_id: &room_id,
+        name: room
+            .display_name()
+            .await
+            .map(|name| name.to_string())
+            .unwrap_or("Unknown Room".to_owned()),
+        at,
+        is_historical,
+        snapshot,
+    };
+    Ok(axum::response::Html(template.render()?).into_response())
+}
+
+/// Resolve a room ID or alias from a URL to a room ID.
+///
+/// Offline, aliases are only resolved to the joined rooms that advertise
+/// them, as the room directory can't be asked.
+async fn resolve_room_id(
+    client: &Client,
+    room_id: &str,
+    timeout: Duration,
+    offline: bool,
+) -> Result<OwnedRoomId, AppError> {
+    if let Ok(alias) = <&RoomAliasId>::try_from(room_id) {
+        if offline {
+            return client
+                .joined_rooms()
+                .into_iter()
+                .find(|room| {
+                    room.canonical_alias().as_deref() == Some(alias)
+                        || room
+                            .alt_aliases()
+                            .iter()
+                            .any(|alt_alias| alt_alias.as_str() == alias.as_str())
+                })
+                .map(|room| room.room_id().to_owned())
+                .ok_or_else(|| {
+                    AppError::Offline(eyre::eyre!(
+                        "Room alias {alias} isn't known locally and can't be resolved offline"
+                    ))
+                });
+        }
+        Ok(with_timeout(timeout, "resolving room alias", async {
+            client
+                .resolve_room_alias(alias)
+                .await
+                .map_err(|err| alias_error(alias, err))
+        })
+        .await?
+        .room_id)
+    } else {
+        OwnedRoomId::try_from(room_id).map_err(|err| {
+            AppError::BadRequest(
+                eyre::Report::new(err).wrap_err("Room ID was not a valid ID or alias!"),
+            )
+        })
+    }
+}
 
-    let room = client.get_room(&room_id).context("Failed to get room")?;
+/// Get a room this archive serves, failing with a 404 for rooms our account
+/// hasn't joined.
+fn joined_room(client: &Client, room_id: &RoomId) -> Result<Room, AppError> {
+    client
+        .get_room(room_id)
+        .filter(|room| room.state() == RoomState::Joined)
+        .ok_or_else(|| AppError::NotFound(eyre::eyre!("Room {room_id} isn't archived here")))
+}
 
-    let Messages {
+async fn render_room(
+    AppState {
+        client,
+        config,
+        time_format,
+        media,
+        pages,
+        ..
+    }: AppState,
+    room_id: &str,
+    from: Option<String>,
+    direction: Direction,
+    query: RoomQuery,
+    raw_query: Option<&str>,
+    headers: &http::HeaderMap,
+) -> Result<axum::response::Response, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    let room_id = resolve_room_id(&client, room_id, timeout, config.account_config.offline).await?;
+
+    download_room_keys(&client, &room_id, timeout, config.account_config.offline).await?;
+
+    let room = joined_room(&client, &room_id)?;
+
+    let is_paginating = from.is_some();
+    // Offline pages link to each other with tokens into the local store, but
+    // links from before going offline carry the homeserver's tokens.
+    if config.account_config.offline
+        && from
+            .as_deref()
+            .is_some_and(|from| !prefetch::is_local_token(from))
+    {
+        return Err(AppError::Offline(eyre::eyre!(
+            "That page of {room_id} isn't stored locally, so it can't be shown offline"
+        )));
+    }
+    // Read before fetching, so events arriving while the page is rendered
+    // make it look older rather than newer than it is.
+    let last_modified = pages.changed_at(&room_id);
+    // The ETag is a hash of the page, so only a date can be checked before
+    // rendering it. Requests with `If-None-Match`, HEAD or not, are rendered
+    // in full.
+    if conditional::is_not_modified(headers, None, Some(last_modified)) {
+        return Ok(conditional::not_modified(conditional::validators(
+            None,
+            Some(last_modified),
+        )));
+    }
+    let page = with_timeout(timeout, "fetching messages", async {
+        Ok::<_, AppError>(match from {
+            Some(from) if matches!(direction, Direction::Forward) => {
+                pages.messages_after(&room, from).await
+            }
+            from => pages.messages(&room, from).await,
+        })
+    })
+    .await?;
+    let prefetch::Page {
         end: token,
+        start: next_batch,
         chunk: mut events,
-        ..
-    } = room
-        .messages(assign!(MessagesOptions::backward(), {limit: 100u8.into()}))
-        .await?;
-    events.reverse();
+    } = match page {
+        Ok(page) => page,
+        // Pagination tokens don't last forever, so links to earlier pages can
+        // stop working. Send the reader back to the room rather than failing.
+        Err(err) if is_paginating && is_invalid_token_error(&err) => {
+            warn!("Pagination token for {room_id} was rejected: {err}");
+            let template = room_to_html::ExpiredLinkTemplate {
+                appearance: config.appearance(),
+                room_id: &room_id,
+                query_suffix: raw_query
+                    .map(|query| format!("?{query}"))
+                    .unwrap_or_default(),
+            };
+            return Ok((
+                http::StatusCode::GONE,
+                axum::response::Html(template.render()?),
+            )
+                .into_response());
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let prefetch_pages = config.render_config.prefetch_pages;
+    if prefetch_pages > 0 {
+        if let Some(token) = &token {
+            pages.prefetch(room.clone(), token.clone(), prefetch_pages.into(), timeout);
+        }
+    }
+    // Checked before any filtering, which can hide the create event.
+    let reached_room_start = has_create_event(&events);
+    // Pages have the newest events first.
+    let order = query.order.unwrap_or(config.render_config.message_order);
+    if order == MessageOrder::Asc {
+        events.reverse();
+    }
 
     // let paginator = Paginator::new(room.clone());
     // paginator.start_from(event_id, num_events)
     // let PaginationResult { events, hit_end_of_timeline } = paginator.paginate_backward(100u8.into()).await?;
 
-    let timeline = stream::iter(events)
-        .then(|i| build_timeline_event(&client, &room_id, i))
+    // `buffered` preserves the order of the events, so the timeline stays in
+    // order while the member lookups run concurrently.
+    let timeline_options = config
+        .render_config
+        .timeline_options(timeout, config.account_config.offline);
+    let mut timeline = stream::iter(events)
+        .map(|i| build_timeline_event(&client, &room_id, i, &timeline_options))
+        .buffered(config.render_config.timeline_concurrency.max(1))
         .try_collect::<Vec<_>>()
         .await?;
+    dedup_events(&mut timeline);
+    aggregate_reactions(&mut timeline);
+    timeline.retain(|event| config.render_config.is_visible(event));
+    if let Some(user) = &query.mentions {
+        timeline.retain(|event| event.mentions(user));
+    }
+    let types_filter = message_types(query.types.as_deref());
+    if !types_filter.is_empty() {
+        timeline.retain(|event| {
+            event.message_type().is_some_and(|message_type| {
+                types_filter.iter().any(|allowed| *allowed == message_type)
+            })
+        });
+    }
+    let order_discrepancy = has_order_discrepancy(&timeline, order);
+    if let Some(joined_at) =
+        room_state::joined_at(&room, timeout, config.account_config.offline).await
+    {
+        for event in &mut timeline {
+            event.sent_before_join = event.timestamp < joined_at;
+        }
+    }
+    let has_pre_join_events = timeline.iter().any(|event| event.sent_before_join);
+    let collapsed_undecryptable = if config.render_config.collapse_undecryptable {
+        undecryptable_count(&timeline)
+    } else {
+        None
+    };
+    let event_sort = config.render_config.event_sort;
+    if event_sort == EventSort::Timestamp {
+        sort_by_timestamp(&mut timeline, order);
+    }
+    let time_format = viewer_time_format(&config, time_format, headers, &query);
+    let timeline = group_timeline(timeline, config.render_config.group_window(), &time_format);
 
     // println!("{timeline:#?}");
+    let join_rule = room.join_rule();
+    let avatar_size = config.render_config.header_avatar_size;
+    let avatar_url = room
+        .avatar_url()
+        .and_then(|url| media.mxc_thumbnail_url(&url, avatar_size, avatar_size));
     let template = RoomTemplate {
+        base_url: public_base_url(&config),
+        appearance: config.appearance(),
         name: room
             .display_name()
             .await
-            .map(|name| name.to_string())
-            .unwrap_or("Unknown Room".to_owned()),
+            .unwrap_or_else(|_| RoomDisplayName::Calculated("Unknown Room".to_owned())),
         room_id: &room_id,
         hit_end_of_timeline: token.is_none(),
+        reached_room_start,
+        is_latest: !is_paginating,
+        mentions_filter: query.mentions.clone(),
+        types_filter,
+        avatar_url,
+        prev_batch: token,
+        next_batch: is_paginating.then_some(next_batch).flatten(),
+        query_suffix: raw_query
+            .map(|query| format!("?{query}"))
+            .unwrap_or_default(),
+        order,
         room: &room,
         events: timeline,
+        time_format,
+        tombstone: room.tombstone(),
+        predecessor: room
+            .create_content()
+            .and_then(|create| create.predecessor)
+            .map(|predecessor| predecessor.room_id),
+        is_thread_view: false,
+        media,
+        order_discrepancy,
+        collapsed_undecryptable,
+        has_pre_join_events,
+        synced_at: room_state::last_synced_at(&room).await,
+        event_sort,
+        join_rule_spaces: room_to_html::join_rule_allowed_rooms(&join_rule)
+            .into_iter()
+            .map(|room_id| {
+                let name = client.get_room(room_id).and_then(|room| room.name());
+                (room_id.to_owned(), name)
+            })
+            .collect(),
+        join_rule,
+        canonical_alias: room.canonical_alias(),
+        density: query.density.unwrap_or(config.render_config.density),
+        content_width: query
+            .width
+            .filter(|width| CONTENT_WIDTH_RANGE.contains(&i64::from(*width)))
+            .unwrap_or(config.render_config.content_width),
+    };
+    let body = template.render()?;
+    let etag = conditional::body_etag(body.as_bytes());
+    let validators = conditional::validators(Some(&etag), Some(last_modified));
+    if conditional::is_not_modified(headers, Some(&etag), Some(last_modified)) {
+        return Ok(conditional::not_modified(validators));
+    }
+    Ok((validators, axum::response::Html(body)).into_response())
+}
+
+/// Render a user's recent messages in each room they can be seen in.
+async fn user(
+    extract::State(AppState {
+        client,
+        config,
+        time_format,
+        media,
+        pages,
+        ..
+    }): extract::State<AppState>,
+    extract::Path(user_id): extract::Path<OwnedUserId>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    let timeline_options = config
+        .render_config
+        .timeline_options(timeout, config.account_config.offline);
+    let time_format = viewer_time_format(&config, time_format, &headers, &query);
+
+    // Only rooms the user is in are looked at, concurrently, so the page
+    // stays quick for large accounts.
+    let mut rooms: Vec<_> = stream::iter(client.joined_rooms())
+        .map(|room| {
+            user_messages_in_room(
+                &client,
+                &config,
+                &pages,
+                &timeline_options,
+                &time_format,
+                &user_id,
+                room,
+            )
+        })
+        .buffer_unordered(config.server_config.room_list_concurrency.into())
+        .try_filter_map(|room| async move { Ok(room) })
+        .try_collect()
+        .await?;
+    // Rooms the user was most recently active in first.
+    rooms.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let template = room_to_html::UserTemplate {
+        base_url: public_base_url(&config),
+        appearance: config.appearance(),
+        user_id: &user_id,
+        rooms: rooms.into_iter().map(|(_, room)| room).collect(),
+        time_format,
+        is_thread_view: false,
+        media,
+    };
+    Ok(axum::response::Html(template.render()?).into_response())
+}
+
+/// The user's recent messages in a room, with the time of the latest, or
+/// `None` if they aren't in the room or haven't sent anything recently.
+async fn user_messages_in_room(
+    client: &Client,
+    config: &Config,
+    pages: &prefetch::PageCache,
+    timeline_options: &TimelineOptions,
+    time_format: &TimeFormat,
+    user_id: &UserId,
+    room: Room,
+) -> Result<
+    Option<(
+        MilliSecondsSinceUnixEpoch,
+        (OwnedRoomId, String, Vec<TimelineItem>),
+    )>,
+    AppError,
+> {
+    let is_member = room
+        .get_member_no_sync(user_id)
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|member| *member.membership() == MembershipState::Join);
+    if !is_member {
+        return Ok(None);
+    }
+    let timeout = config.server_config.upstream_timeout();
+    let room_id = room.room_id().to_owned();
+    let page = match with_timeout(timeout, "fetching messages", pages.recent_messages(&room)).await
+    {
+        Ok(page) => page,
+        Err(err) => {
+            warn!(
+                "Skipping {room_id} on the page for {user_id}: {}",
+                err.report
+            );
+            return Ok(None);
+        }
+    };
+    // The page is newest first, so this keeps the most recent messages.
+    let mut events: Vec<_> = page
+        .chunk
+        .into_iter()
+        .filter(|event| {
+            event
+                .raw()
+                .get_field::<OwnedUserId>("sender")
+                .ok()
+                .flatten()
+                .is_some_and(|sender| *sender == *user_id)
+        })
+        .take(config.render_config.user_page_limit)
+        .collect();
+    if events.is_empty() {
+        return Ok(None);
+    }
+    events.reverse();
+
+    // Failing to download keys only means some messages can't be
+    // decrypted, so it shouldn't stop the other rooms from rendering.
+    let _ = download_room_keys(client, &room_id, timeout, config.account_config.offline).await;
+    let mut timeline = stream::iter(events)
+        .map(|i| build_timeline_event(client, &room_id, i, timeline_options))
+        .buffered(config.render_config.timeline_concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    dedup_events(&mut timeline);
+    aggregate_reactions(&mut timeline);
+    timeline.retain(|event| config.render_config.is_visible(event));
+    let Some(latest) = timeline.last().map(|event| event.timestamp) else {
+        return Ok(None);
+    };
+    let timeline = group_timeline(timeline, config.render_config.group_window(), time_format);
+
+    let name = room
+        .display_name()
+        .await
+        .map(|name| name.to_string())
+        .unwrap_or("Unknown Room".to_owned());
+    Ok(Some((latest, (room_id, name, timeline))))
+}
+
+/// Render a thread, with its root at the top.
+async fn thread(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((room_id, thread_root)): extract::Path<(String, OwnedEventId)>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    render_thread(
+        state,
+        &room_id,
+        thread_root,
+        None,
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// Render the rest of a thread that was too long for one page, after a
+/// pagination token.
+async fn thread_after(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((room_id, thread_root, token)): extract::Path<(String, OwnedEventId, String)>,
+    extract::Query(query): extract::Query<RoomQuery>,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    render_thread(
+        state,
+        &room_id,
+        thread_root,
+        Some(token),
+        query,
+        uri.query(),
+        &headers,
+    )
+    .await
+}
+
+/// Render up to `--history-search-pages` pages of a thread's replies from
+/// `from`, or from the start of the thread.
+async fn render_thread(
+    AppState {
+        client,
+        config,
+        time_format,
+        media,
+        ..
+    }: AppState,
+    room_id: &str,
+    thread_root: OwnedEventId,
+    mut from: Option<String>,
+    query: RoomQuery,
+    raw_query: Option<&str>,
+    headers: &http::HeaderMap,
+) -> Result<axum::response::Response, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    if config.account_config.offline {
+        return Err(AppError::Offline(eyre::eyre!(
+            "Threads can't be fetched offline"
+        )));
+    }
+    let room_id = resolve_room_id(&client, room_id, timeout, config.account_config.offline).await?;
+    download_room_keys(&client, &room_id, timeout, config.account_config.offline).await?;
+    let room = joined_room(&client, &room_id)?;
+
+    let root = with_timeout(
+        timeout,
+        "fetching thread root",
+        room.event(&thread_root, None),
+    )
+    .await?;
+    let mut events = vec![root];
+    // Long threads are cut off after as many pages as a history search, with
+    // a link to continue from there.
+    for _ in 0..config.server_config.history_search_pages {
+        let relations = with_timeout(
+            timeout,
+            "fetching thread",
+            room.relations(
+                thread_root.clone(),
+                RelationsOptions {
+                    from,
+                    dir: Direction::Forward,
+                    include_relations: IncludeRelations::RelationsOfType(RelationType::Thread),
+                    ..Default::default()
+                },
+            ),
+        )
+        .await?;
+        events.extend(relations.chunk);
+        from = relations.next_batch_token;
+        if from.is_none() {
+            break;
+        }
+    }
+
+    let timeline_options = config
+        .render_config
+        .timeline_options(timeout, config.account_config.offline);
+    let mut timeline = stream::iter(events)
+        .map(|i| build_timeline_event(&client, &room_id, i, &timeline_options))
+        .buffered(config.render_config.timeline_concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    dedup_events(&mut timeline);
+    aggregate_reactions(&mut timeline);
+    timeline.retain(|event| config.render_config.is_visible(event));
+    let time_format = viewer_time_format(&config, time_format, headers, &query);
+    let timeline = group_timeline(timeline, config.render_config.group_window(), &time_format);
+
+    let template = room_to_html::ThreadTemplate {
+        base_url: public_base_url(&config),
+        appearance: config.appearance(),
+        name: room
+            .display_name()
+            .await
+            .map(|name| name.to_string())
+            .unwrap_or("Unknown Room".to_owned()),
+        room_id: &room_id,
+        thread_root: &thread_root,
+        events: timeline,
+        next_batch: from,
+        query_suffix: raw_query
+            .map(|query| format!("?{query}"))
+            .unwrap_or_default(),
+        time_format,
+        is_thread_view: true,
+        media,
+    };
+    Ok(axum::response::Html(template.render()?).into_response())
+}
+
+/// The most media messages shown on one page of a room's media gallery.
+const GALLERY_PAGE_SIZE: usize = 60;
+
+#[derive(Debug, Deserialize)]
+struct GalleryQuery {
+    /// The pagination token to continue the gallery from.
+    from: Option<String>,
+    /// IANA time zone to display timestamps in.
+    tz: Option<String>,
+}
+
+/// Render an index of the images, videos and files sent in a room, walking
+/// back through a bounded amount of history per page.
+async fn media_gallery(
+    extract::State(AppState {
+        client,
+        config,
+        time_format,
+        media,
+        pages,
+        ..
+    }): extract::State<AppState>,
+    extract::Path(room_id): extract::Path<String>,
+    extract::Query(GalleryQuery { from, tz }): extract::Query<GalleryQuery>,
+    headers: http::HeaderMap,
+) -> Result<impl axum::response::IntoResponse, AppError> {
+    let timeout = config.server_config.upstream_timeout();
+    let room_id =
+        resolve_room_id(&client, &room_id, timeout, config.account_config.offline).await?;
+    download_room_keys(&client, &room_id, timeout, config.account_config.offline).await?;
+    let room = joined_room(&client, &room_id)?;
+
+    let timeline_options = TimelineOptions {
+        reply_depth: 0,
+        ..config
+            .render_config
+            .timeline_options(timeout, config.account_config.offline)
+    };
+    let mut items = Vec::new();
+    let mut from = from;
+    for _ in 0..config.server_config.gallery_search_pages {
+        let page = with_timeout(
+            timeout,
+            "fetching messages",
+            pages.messages(&room, from.clone()),
+        )
+        .await?;
+        let media_events = page.chunk.into_iter().filter(|event| {
+            let msgtype = event.raw().get_field::<String>("type").ok().flatten();
+            msgtype.as_deref() == Some("m.room.message")
+        });
+        let mut timeline = stream::iter(media_events)
+            .map(|i| build_timeline_event(&client, &room_id, i, &timeline_options))
+            .buffered(config.render_config.timeline_concurrency.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+        dedup_events(&mut timeline);
+        items.extend(
+            timeline
+                .into_iter()
+                .filter(|event| room_to_html::content_attachment(&event.content).is_some())
+                .map(|event| room_to_html::GalleryItem {
+                    page: from.clone(),
+                    event,
+                }),
+        );
+        from = page.end;
+        if from.is_none() || items.len() >= GALLERY_PAGE_SIZE {
+            break;
+        }
+    }
+
+    let query = RoomQuery {
+        tz,
+        ..Default::default()
+    };
+    let template = room_to_html::MediaGalleryTemplate {
+        appearance: config.appearance(),
+        name: room
+            .display_name()
+            .await
+            .map(|name| name.to_string())
+            .unwrap_or("Unknown Room".to_owned()),
+        room_id: &room_id,
+        items,
+        next_from: from,
+        time_format: viewer_time_format(&config, time_format, &headers, &query),
+        media,
     };
     Ok(axum::response::Html(template.render()?).into_response())
 }
 
-struct AppError(eyre::Report);
+/// Download the keys for a room from the key backup, so its history can be
+/// decrypted.
+async fn download_room_keys(
+    client: &Client,
+    room_id: &ruma::RoomId,
+    timeout: Duration,
+    offline: bool,
+) -> Result<(), AppError> {
+    // Only keys already in the local store can be used offline.
+    if offline {
+        return Ok(());
+    }
+    with_timeout(
+        timeout,
+        "downloading room keys",
+        client
+            .encryption()
+            .backups()
+            .download_room_keys_for_room(room_id),
+    )
+    .await
+    .inspect_err(|e| {
+        error!(
+            "Failed to download room keys for room {room_id}: {}",
+            e.report
+        );
+    })
+}
 
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> axum::response::Response {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
+/// The time format to render a page with, taking the viewer's preferences
+/// into account if enabled.
+fn viewer_time_format(
+    config: &Config,
+    time_format: TimeFormat,
+    headers: &http::HeaderMap,
+    query: &RoomQuery,
+) -> TimeFormat {
+    if config.render_config.per_viewer_time_format {
+        time_format.for_viewer(
+            headers
+                .get(http::header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok()),
+            query.tz.as_deref(),
         )
-            .into_response()
+    } else {
+        time_format
     }
 }
 
-impl<E> From<E> for AppError
+/// The scheme and host the archive is served at from `--public-url`, for
+/// absolute links to it, or nothing for relative links.
+///
+/// The request's `Host` and `X-Forwarded-Proto` headers aren't used, as any
+/// client can set them to put its own host in printed links.
+fn public_base_url(config: &Config) -> String {
+    config
+        .server_config
+        .public_url
+        .as_deref()
+        .map(|public_url| public_url.trim_end_matches('/').to_owned())
+        .unwrap_or_default()
+}
+
+/// Whether an error from paginating means the pagination token is no longer
+/// valid.
+///
+/// Other failures, such as the homeserver being overloaded, are reported as
+/// they are rather than as an expired link.
+fn is_invalid_token_error(err: &matrix_sdk::Error) -> bool {
+    let Some(err) = err.as_client_api_error() else {
+        return false;
+    };
+    match &err.body {
+        ErrorBody::Standard {
+            kind: ErrorKind::InvalidParam,
+            ..
+        } => true,
+        // Some homeservers reject unparseable tokens without a specific code.
+        ErrorBody::Standard {
+            kind: ErrorKind::Unknown,
+            message,
+        } => err.status_code.as_u16() == 400 && message.to_lowercase().contains("token"),
+        _ => false,
+    }
+}
+
+/// Wait for a request to the homeserver, failing with a 504 if it takes longer
+/// than `timeout`.
+async fn with_timeout<T, E>(
+    timeout: Duration,
+    action: &str,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, AppError>
 where
-    E: Into<eyre::Report>,
+    E: Into<AppError>,
 {
-    fn from(err: E) -> Self {
-        Self(err.into())
+    match tokio::time::timeout(timeout, request).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(AppError::Timeout(eyre::eyre!("Timed out {action}"))),
     }
 }
+
+/// Map a failure to resolve a room alias to an error, distinguishing aliases
+/// that don't exist from homeservers that couldn't be reached.
+fn alias_error(alias: &RoomAliasId, err: matrix_sdk::HttpError) -> AppError {
+    match err.client_api_error_kind() {
+        Some(ErrorKind::NotFound) => {
+            AppError::NotFound(eyre::eyre!("Room alias {alias} does not exist"))
+        }
+        _ => AppError::Upstream(
+            eyre::Report::new(err).wrap_err(format!("Couldn't resolve room alias {alias}")),
+        ),
+    }
+}
+
 /// Restore a previous session.
-async fn restore_session(session_file: &Path) -> eyre::Result<(Client, Option<String>)> {
+async fn restore_session(
+    session_file: &Path,
+    config: &AccountConfig,
+) -> eyre::Result<(Client, Option<String>)> {
     info!(
         "Previous session found in '{}'",
         session_file.to_string_lossy()
@@ -378,8 +2339,8 @@ async fn restore_session(session_file: &Path) -> eyre::Result<(Client, Option<St
     } = serde_json::from_str(&serialized_session)?;
 
     // Build the client with the previous settings from the session.
-    let client = Client::builder()
-        .homeserver_url(client_session.homeserver)
+    let client = config
+        .client_builder(&client_session.homeserver)
         .sqlite_store(client_session.db_path, Some(&client_session.passphrase))
         .build()
         .await?;
@@ -389,58 +2350,67 @@ async fn restore_session(session_file: &Path) -> eyre::Result<(Client, Option<St
     // Restore the Matrix user session.
     client.restore_session(user_session).await?;
 
-    verify_device(client.encryption(), None).await?;
+    verify_device(client.encryption(), config).await?;
 
     Ok((client, sync_token))
 }
 
 /// Login to a new session.
+///
+/// Without a session file to persist the session to, the client's stores are
+/// kept in memory.
 async fn login(
     data_dir: &std::path::Path,
-    session_file: &std::path::Path,
+    session_file: Option<&std::path::Path>,
     config: &AccountConfig,
 ) -> eyre::Result<Client> {
     info!("No previous session found, logging in…");
-    let mut rng = rand::rng();
 
-    // Generate a random passphrase.
-    let passphrase: String = (&mut rng)
-        .sample_iter(Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
+    let (client, client_session) = if session_file.is_some() {
+        let mut rng = rand::rng();
 
-    let db_subfolder: String = (&mut rng)
-        .sample_iter(Alphanumeric)
-        .take(7)
-        .map(char::from)
-        .collect();
-    let db_path = data_dir.join(db_subfolder);
-
-    let client = Client::builder()
-        .homeserver_url(&config.server)
-        .sqlite_store(&db_path, Some(&passphrase))
-        .build()
-        .await?;
+        // Generate a random passphrase.
+        let passphrase: String = (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
 
-    let client_session = ClientSession {
-        homeserver: config.server.clone(),
-        db_path,
-        passphrase,
+        let db_subfolder: String = (&mut rng)
+            .sample_iter(Alphanumeric)
+            .take(7)
+            .map(char::from)
+            .collect();
+        let db_path = data_dir.join(db_subfolder);
+
+        let client = config
+            .client_builder(&config.server)
+            .sqlite_store(&db_path, Some(&passphrase))
+            .build()
+            .await?;
+
+        let client_session = ClientSession {
+            homeserver: config.server.clone(),
+            db_path,
+            passphrase,
+        };
+        (client, Some(client_session))
+    } else {
+        info!("Using an in-memory store, nothing will be persisted");
+        let client = config.client_builder(&config.server).build().await?;
+        (client, None)
     };
     let matrix_auth = client.matrix_auth();
 
+    let username = config
+        .username
+        .as_deref()
+        .context("A username is needed to log in")?;
     loop {
-        let username = &config.username;
-        let password = config.password.clone().unwrap_or_else(|| {
-            println!("Type password for the bot (characters won't show up as you type them)");
-            match prompt_password("Password: ") {
-                Ok(p) => p,
-                Err(err) => {
-                    panic!("FATAL: failed to get password: {err}");
-                }
-            }
-        });
+        let password = match config.password.clone() {
+            Some(password) => password,
+            None => prompt_secret(config, "password for the bot", "Password: ")?,
+        };
 
         match matrix_auth
             .login_username(username, &password)
@@ -460,7 +2430,11 @@ async fn login(
         }
     }
 
-    verify_device(client.encryption(), config.recovery_key.clone()).await?;
+    verify_device(client.encryption(), config).await?;
+
+    let (Some(session_file), Some(client_session)) = (session_file, client_session) else {
+        return Ok(client);
+    };
 
     // Persist the session to reuse it later.
     // This is not very secure, for simplicity. If the system provides a way of
@@ -474,6 +2448,9 @@ async fn login(
         user_session,
         sync_token: None,
     })?;
+    if let Some(parent) = session_file.parent() {
+        fs::create_dir_all(parent).await?;
+    }
     fs::write(session_file, serialized_session).await?;
 
     info!("Session persisted in {}", session_file.to_string_lossy());
@@ -481,28 +2458,110 @@ async fn login(
     Ok(client)
 }
 
-async fn verify_device(encryption: Encryption, recovery_key: Option<String>) -> eyre::Result<()> {
-    let device = encryption
+/// Register a guest account, and join the configured rooms with it.
+///
+/// Guest accounts can't be logged back into, so the session is kept in
+/// memory.
+async fn guest_login(config: &AccountConfig) -> eyre::Result<Client> {
+    info!("Registering a guest account…");
+    let client = config.client_builder(&config.server).build().await?;
+    client
+        .matrix_auth()
+        .register(assign!(register::v3::Request::new(), {
+            kind: register::RegistrationKind::Guest,
+            initial_device_display_name: Some(config.device_name.clone()),
+        }))
+        .await
+        .wrap_err("Failed to register a guest account")?;
+    info!(
+        "Registered as guest {}",
+        client
+            .user_id()
+            .expect("A registered client should have a user ID")
+    );
+
+    for room in &config.guest_rooms {
+        client
+            .join_room_by_id_or_alias(room, &[])
+            .await
+            .wrap_err_with(|| format!("Failed to join {room} as a guest"))?;
+        info!("Joined {room} as a guest");
+    }
+
+    Ok(client)
+}
+
+/// Ask for a secret on the terminal, or fail if prompting is disabled.
+fn prompt_secret(config: &AccountConfig, description: &str, prompt: &str) -> eyre::Result<String> {
+    if config.non_interactive {
+        eyre::bail!(
+            "No {description} was provided, and prompting is disabled by --non-interactive"
+        );
+    }
+    println!("Type {description} (characters won't show up as you type them)");
+    prompt_password(prompt).wrap_err_with(|| format!("Failed to get {description}"))
+}
+
+async fn verify_device(encryption: Encryption, config: &AccountConfig) -> eyre::Result<()> {
+    let mut device = encryption
         .get_own_device()
         .await?
         .expect("to have a device");
 
+    if !device.is_verified_with_cross_signing() && config.bootstrap_cross_signing {
+        info!("Bootstrapping cross-signing");
+        let auth_data =
+            config
+                .username
+                .clone()
+                .zip(config.password.clone())
+                .map(|(username, password)| {
+                    AuthData::Password(Password::new(
+                        UserIdentifier::UserIdOrLocalpart(username),
+                        password,
+                    ))
+                });
+        match encryption
+            .bootstrap_cross_signing_if_needed(auth_data)
+            .await
+        {
+            Ok(()) => {
+                device = encryption
+                    .get_own_device()
+                    .await?
+                    .expect("to have a device");
+            }
+            Err(e) => error!("Failed to bootstrap cross-signing: {e}"),
+        }
+    }
+
     if device.is_verified_with_cross_signing() {
         info!(
             "Device {} of user {} is verified",
             device.device_id(),
             device.user_id(),
         );
+    } else if config.no_verify {
+        warn!(
+            "Device {} of user {} is not verified, continuing without verification. Some messages may be unable to be decrypted",
+            device.device_id(),
+            device.user_id(),
+        );
     } else {
         info!(
             "Device {} of user {} is not verified",
             device.device_id(),
             device.user_id(),
         );
-        let recovery_key = recovery_key.or_else(|| {
-            println!("Type recovery key for the bot (characters won't show up as you type them)");
-            prompt_password("Recovery Key: ").ok()
-        });
+        let recovery_key = match config.recovery_key.clone() {
+            Some(recovery_key) => Some(recovery_key),
+            None if config.non_interactive => eyre::bail!(
+                "Device is not verified and no recovery key was provided. Provide a recovery key, or pass --no-verify to continue unverified"
+            ),
+            None => prompt_secret(config, "recovery key for the bot", "Recovery Key: ")
+                .inspect_err(|e| error!("{e}"))
+                .ok(),
+        };
         if let Some(recovery_key) = recovery_key {
             info!("Trying to recover device");
             let _ = encryption
@@ -521,12 +2580,23 @@ async fn verify_device(encryption: Encryption, recovery_key: Option<String>) ->
 async fn run(
     client: &Client,
     initial_sync_token: Option<String>,
-    session_file: &Path,
+    session_file: Option<&Path>,
     config: &Config,
+    progress: &readiness::SyncProgress,
 ) -> eyre::Result<()> {
-    // handler for autojoin
-    // Handers here run for historic messages too
-    // client.add_event_handler(crate::handlers::on_stripped_state_member);
+    // Handlers here run for historic events too, so invites received while
+    // offline are accepted.
+    if config.account_config.autojoin {
+        if config.account_config.autojoin_from.is_empty() {
+            warn!(
+                "No invites will be accepted, as --autojoin-from is empty. Use '*' to accept invites from anyone"
+            );
+        }
+        client.add_event_handler_context(handlers::AutojoinPolicy {
+            allowed_inviters: config.account_config.autojoin_from.clone(),
+        });
+        client.add_event_handler(handlers::on_stripped_state_member);
+    }
 
     info!("Launching a first sync...");
 
@@ -549,19 +2619,29 @@ async fn run(
     // server should cache the response and it will ultimately take less time to
     // receive.
     loop {
-        match client.sync_once(sync_settings.clone()).await {
+        let result = client.sync_once(sync_settings.clone()).await;
+        progress.record_attempt(client.rooms().len());
+        match result {
             Ok(response) => {
                 // This is the last time we need to provide this token, the sync method after
                 // will handle it on its own.
-                persist_sync_token(session_file, response.next_batch).await?;
+                if let Some(session_file) = session_file {
+                    persist_sync_token(session_file, response.next_batch).await?;
+                }
                 break;
             }
             Err(error) => {
                 warn!("An error occurred during initial sync: {error}");
+                info!(
+                    "Syncing: {} rooms so far, after {} attempts",
+                    progress.rooms(),
+                    progress.attempts()
+                );
             }
         }
     }
-    info!("Initial sync done");
+    progress.finish(client.rooms().len());
+    info!("Initial sync done: {} rooms", progress.rooms());
 
     let current_session = client.device_id().map(|d| d.to_owned());
     if config.account_config.delete_other_devices {
@@ -578,6 +2658,14 @@ async fn run(
             .map(|device| device.device_id.clone())
             .collect();
         if !other_devices.is_empty() {
+            let password = match config.account_config.password.clone() {
+                Some(password) => password,
+                None => prompt_secret(
+                    &config.account_config,
+                    "password for the account",
+                    "Password: ",
+                )?,
+            };
             trace!(
                 current_session = format!("{current_session:?}"),
                 other_devices = format!("{other_devices:?}"),
@@ -587,18 +2675,13 @@ async fn run(
                 .delete_devices(
                     &other_devices,
                     Some(AuthData::Password(Password::new(
-                        UserIdentifier::UserIdOrLocalpart(config.account_config.username.clone()),
-                        config.account_config.password.clone().unwrap_or_else(|| {
-                            println!(
-                            "Type password for the account (characters won't show up as you type them)"
-                        );
-                            match prompt_password("Password: ") {
-                                Ok(p) => p,
-                                Err(err) => {
-                                    panic!("FATAL: failed to get password: {err}");
-                                }
-                            }
-                        }),
+                        UserIdentifier::UserIdOrLocalpart(
+                            client
+                                .user_id()
+                                .expect("A logged-in client should have a user ID")
+                                .to_string(),
+                        ),
+                        password,
                     ))),
                 )
                 .await?;
@@ -623,6 +2706,102 @@ async fn run(
     Ok(())
 }
 
+/// Periodically clear the whole event cache of rooms holding more than
+/// `threshold` events, so it can't grow without bound.
+///
+/// The event cache can't drop only its oldest events, so cleared rooms have
+/// to fetch their history from the homeserver again the next time they are
+/// rendered.
+async fn clear_large_event_caches(client: Client, threshold: usize) {
+    let mut interval = tokio::time::interval(EVENT_CACHE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        for room in client.joined_rooms() {
+            let room_cache = match client.event_cache().for_room(room.room_id()).await {
+                Ok((room_cache, _drop_handles)) => room_cache,
+                Err(err) => {
+                    warn!(
+                        "Failed to get the event cache for {}: {err}",
+                        room.room_id()
+                    );
+                    continue;
+                }
+            };
+            let cached = room_cache.events().await.len();
+            if cached > threshold {
+                info!(
+                    "Clearing {cached} cached events for {}, over the threshold of {threshold}",
+                    room.room_id()
+                );
+                if let Err(err) = room_cache.clear().await {
+                    warn!(
+                        "Failed to clear the event cache for {}: {err}",
+                        room.room_id()
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The shortest media signing key that's used, whether configured or read
+/// from the data directory.
+const MIN_MEDIA_SIGNING_KEY_LEN: usize = 16;
+
+/// Load the key used to sign media URLs, generating and storing one if it
+/// hasn't been configured.
+///
+/// Without a data directory, the generated key only lasts for this run.
+async fn media_signing_key(
+    config: &ServerConfig,
+    data_dir: Option<&Path>,
+) -> eyre::Result<Vec<u8>> {
+    if let Some(key) = &config.media_signing_key {
+        if key.len() < MIN_MEDIA_SIGNING_KEY_LEN {
+            eyre::bail!(
+                "--media-signing-key is only {} bytes long, it must be at least {MIN_MEDIA_SIGNING_KEY_LEN}",
+                key.len()
+            );
+        }
+        return Ok(key.as_bytes().to_vec());
+    }
+    let Some(data_dir) = data_dir else {
+        return Ok(rand::random::<[u8; 32]>().to_vec());
+    };
+
+    let key_file = data_dir.join("media_signing_key");
+    if key_file.exists() {
+        let key = fs::read(&key_file).await?;
+        if key.len() < MIN_MEDIA_SIGNING_KEY_LEN {
+            eyre::bail!(
+                "The media signing key in {} is only {} bytes long. Delete it to generate a new one",
+                key_file.display(),
+                key.len()
+            );
+        }
+        return Ok(key);
+    }
+
+    info!(
+        "Generating media signing key in {}",
+        key_file.to_string_lossy()
+    );
+    let key: [u8; 32] = rand::random();
+    fs::create_dir_all(data_dir).await?;
+    // Anyone who can read the key can mint media URLs, so only we may.
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&key_file).await?;
+    {
+        use tokio::io::AsyncWriteExt;
+        file.write_all(&key).await?;
+        file.flush().await?;
+    }
+    Ok(key.to_vec())
+}
+
 /// Persist the sync token for a future session.
 /// Note that this is needed only when using `sync_once`. Other sync methods get
 /// the sync token from the store.
@@ -0,0 +1,359 @@
+//! A proxy for Matrix media, so that it can be viewed without a Matrix account.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract, http,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use color_eyre::eyre;
+use hmac::{Hmac, Mac};
+use matrix_sdk::{
+    Client,
+    event_cache_store::EventCacheStore,
+    media::{MediaFormat, MediaRequestParameters, MediaThumbnailSettings},
+};
+use ruma::{
+    MxcUri, OwnedMxcUri,
+    api::client::{authenticated_media, media as legacy_media},
+    events::room::MediaSource,
+};
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+
+use crate::{AppError, AppState, conditional, with_timeout};
+
+/// Mints signed URLs for media referenced by rendered pages, which the proxy
+/// is allowed to serve.
+///
+/// Proxy URLs carry an HMAC over the media and thumbnail size, and requests
+/// without a valid signature are refused, so the proxy can't be used to probe
+/// or relay arbitrary media on the homeserver.
+///
+/// The sources of minted media are kept so that encrypted media, whose keys
+/// can't be put in the URL, can be decrypted when it is requested. They're
+/// kept for [`MEDIA_TTL`] after they were last minted, and at most
+/// [`MAX_MEDIA`] at once, so rendering pages can't grow them without limit.
+#[derive(Clone, Debug)]
+pub struct MediaRegistry {
+    signing_key: Arc<[u8]>,
+    media: Arc<Mutex<HashMap<OwnedMxcUri, (Instant, MediaEntry)>>>,
+    /// Bounds how many media fetches run at once. Requests over the limit
+    /// wait for a permit.
+    fetch_permits: Arc<Semaphore>,
+}
+
+/// How long the source of minted media is kept after it was last minted.
+const MEDIA_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The most media sources kept at once.
+const MAX_MEDIA: usize = 10_000;
+
+#[derive(Clone, Debug)]
+struct MediaEntry {
+    source: MediaSource,
+    mimetype: Option<String>,
+}
+
+impl MediaRegistry {
+    pub fn new(signing_key: &[u8], max_concurrent_fetches: usize) -> Self {
+        Self {
+            signing_key: signing_key.into(),
+            media: Default::default(),
+            fetch_permits: Arc::new(Semaphore::new(max_concurrent_fetches)),
+        }
+    }
+
+    /// Mint a proxy URL for a media source.
+    pub fn url(&self, source: &MediaSource, mimetype: Option<&str>) -> Option<String> {
+        self.mint(source, mimetype, None)
+    }
+
+    /// Mint a proxy URL for a thumbnail of a media source.
+    pub fn thumbnail_url(
+        &self,
+        source: &MediaSource,
+        mimetype: Option<&str>,
+        width: u32,
+        height: u32,
+    ) -> Option<String> {
+        self.mint(source, mimetype, Some((width, height)))
+    }
+
+    /// Mint a proxy URL for unencrypted media, such as an avatar.
+    pub fn mxc_url(&self, mxc: &MxcUri) -> Option<String> {
+        self.url(&MediaSource::Plain(mxc.to_owned()), None)
+    }
+
+    /// Mint a proxy URL for a thumbnail of unencrypted media, such as an
+    /// avatar.
+    pub fn mxc_thumbnail_url(&self, mxc: &MxcUri, width: u32, height: u32) -> Option<String> {
+        self.thumbnail_url(&MediaSource::Plain(mxc.to_owned()), None, width, height)
+    }
+
+    fn mint(
+        &self,
+        source: &MediaSource,
+        mimetype: Option<&str>,
+        size: Option<(u32, u32)>,
+    ) -> Option<String> {
+        let mxc = match source {
+            MediaSource::Plain(mxc) => mxc.clone(),
+            MediaSource::Encrypted(file) => file.url.clone(),
+        };
+        let (server_name, media_id) = mxc.parts().ok()?;
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(self.mac(&mxc, size).finalize().into_bytes());
+        let url = match size {
+            Some((width, height)) => format!(
+                "/media/{server_name}/{media_id}?width={width}&height={height}&sig={signature}"
+            ),
+            None => format!("/media/{server_name}/{media_id}?sig={signature}"),
+        };
+
+        // Unencrypted media with no known type is served the same without an
+        // entry, so there's nothing to keep.
+        if matches!(source, MediaSource::Encrypted(_)) || mimetype.is_some() {
+            self.insert(
+                mxc,
+                MediaEntry {
+                    source: source.clone(),
+                    mimetype: mimetype.map(ToOwned::to_owned),
+                },
+            );
+        }
+        Some(url)
+    }
+
+    fn insert(&self, mxc: OwnedMxcUri, entry: MediaEntry) {
+        let mut media = self.media.lock().unwrap();
+        if media.len() >= MAX_MEDIA && !media.contains_key(&mxc) {
+            media.retain(|_, (minted_at, _)| minted_at.elapsed() < MEDIA_TTL);
+            // Make room by forgetting the media minted longest ago.
+            if media.len() >= MAX_MEDIA {
+                let oldest = media
+                    .iter()
+                    .min_by_key(|(_, (minted_at, _))| *minted_at)
+                    .map(|(mxc, _)| mxc.clone());
+                if let Some(oldest) = oldest {
+                    media.remove(&oldest);
+                }
+            }
+        }
+        media.insert(mxc, (Instant::now(), entry));
+    }
+
+    fn mac(&self, mxc: &MxcUri, size: Option<(u32, u32)>) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.signing_key).expect("HMAC accepts any key length");
+        mac.update(mxc.as_str().as_bytes());
+        if let Some((width, height)) = size {
+            mac.update(format!("?width={width}&height={height}").as_bytes());
+        }
+        mac
+    }
+
+    fn verify(&self, mxc: &MxcUri, size: Option<(u32, u32)>, signature: &str) -> bool {
+        BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .is_ok_and(|signature| self.mac(mxc, size).verify_slice(&signature).is_ok())
+    }
+
+    fn get(&self, mxc: &MxcUri) -> Option<MediaEntry> {
+        let media = self.media.lock().unwrap();
+        let (minted_at, entry) = media.get(mxc)?;
+        (minted_at.elapsed() < MEDIA_TTL).then(|| entry.clone())
+    }
+}
+
+/// Which homeserver endpoints media is fetched from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MediaEndpoint {
+    /// Use authenticated media if the homeserver advertises a Matrix version
+    /// that supports it, and the legacy endpoints otherwise
+    #[default]
+    Auto,
+    /// Always use the authenticated `/_matrix/client/v1/media` endpoints
+    Authenticated,
+    /// Always use the legacy unauthenticated `/_matrix/media/v3` endpoints
+    Legacy,
+}
+
+/// Fetch media from the homeserver using the configured endpoints.
+///
+/// Encrypted media always goes through the SDK, which decrypts it, so it
+/// follows the homeserver's advertised versions regardless of `endpoint`.
+async fn fetch_media(
+    client: &Client,
+    request: &MediaRequestParameters,
+    endpoint: MediaEndpoint,
+) -> matrix_sdk::Result<Vec<u8>> {
+    let mxc = match (endpoint, &request.source) {
+        (MediaEndpoint::Auto, _) | (_, MediaSource::Encrypted(_)) => {
+            return client.media().get_media_content(request, true).await;
+        }
+        (_, MediaSource::Plain(mxc)) => mxc,
+    };
+    let (server_name, media_id) = mxc
+        .parts()
+        .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
+    let (server_name, media_id) = (server_name.to_owned(), media_id.to_owned());
+
+    let data = match (endpoint, &request.format) {
+        (MediaEndpoint::Authenticated, MediaFormat::File) => {
+            let request = authenticated_media::get_content::v1::Request::new(media_id, server_name);
+            client.send(request).await?.file
+        }
+        (MediaEndpoint::Authenticated, MediaFormat::Thumbnail(settings)) => {
+            let request = authenticated_media::get_content_thumbnail::v1::Request::new(
+                media_id,
+                server_name,
+                settings.width,
+                settings.height,
+            );
+            client.send(request).await?.file
+        }
+        #[allow(deprecated)]
+        (_, MediaFormat::File) => {
+            let request = legacy_media::get_content::v3::Request::new(media_id, server_name);
+            client.send(request).await?.file
+        }
+        #[allow(deprecated)]
+        (_, MediaFormat::Thumbnail(settings)) => {
+            let request = legacy_media::get_content_thumbnail::v3::Request::new(
+                media_id,
+                server_name,
+                settings.width,
+                settings.height,
+            );
+            client.send(request).await?.file
+        }
+    };
+    Ok(data)
+}
+
+/// Media from the local media cache, for serving offline.
+async fn stored_media(
+    client: &Client,
+    request: &MediaRequestParameters,
+) -> eyre::Result<Option<Vec<u8>>> {
+    let store = client.event_cache_store().lock().await?;
+    Ok(store.get_media_content(request).await?)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaQuery {
+    width: Option<u32>,
+    height: Option<u32>,
+    sig: Option<String>,
+}
+
+pub async fn media(
+    extract::State(AppState {
+        client,
+        config,
+        media,
+        ..
+    }): extract::State<AppState>,
+    extract::Path((server_name, media_id)): extract::Path<(String, String)>,
+    extract::Query(query): extract::Query<MediaQuery>,
+    request_headers: http::HeaderMap,
+) -> Result<Response, AppError> {
+    let mxc = OwnedMxcUri::from(format!("mxc://{server_name}/{media_id}"));
+    let size = query.width.zip(query.height);
+    let Some(signature) = query
+        .sig
+        .filter(|signature| media.verify(&mxc, size, signature))
+    else {
+        return Err(AppError::Forbidden(eyre::eyre!(
+            "Invalid signature for media {mxc}"
+        )));
+    };
+
+    // Media in the content repository is immutable, and the signature covers
+    // the media and thumbnail size, so it identifies the response. Copies
+    // that are still current are confirmed without fetching the media, for
+    // HEAD requests too.
+    let etag = format!("\"{signature}\"");
+    let mut validators = conditional::validators(Some(&etag), None);
+    validators.insert(
+        http::header::CACHE_CONTROL,
+        http::HeaderValue::from_static("public, max-age=31536000, immutable"),
+    );
+    if conditional::is_not_modified(&request_headers, Some(&etag), None) {
+        return Ok(conditional::not_modified(validators));
+    }
+
+    // Media minted before a restart, or too long ago, is forgotten, but
+    // unencrypted media can still be fetched from its signed URL.
+    let MediaEntry { source, mimetype } = media.get(&mxc).unwrap_or_else(|| MediaEntry {
+        source: MediaSource::Plain(mxc.clone()),
+        mimetype: None,
+    });
+
+    // Encrypted media can't be thumbnailed by the homeserver.
+    let format = match (&source, size) {
+        (MediaSource::Plain(_), Some((width, height))) => {
+            MediaFormat::Thumbnail(MediaThumbnailSettings::new(width.into(), height.into()))
+        }
+        _ => MediaFormat::File,
+    };
+    let is_thumbnail = matches!(format, MediaFormat::Thumbnail(_));
+    let request = MediaRequestParameters { source, format };
+    let _permit = media
+        .fetch_permits
+        .acquire()
+        .await
+        .expect("the media semaphore is never closed");
+    let data = if config.account_config.offline {
+        stored_media(&client, &request)
+            .await?
+            .ok_or_else(|| AppError::Offline(eyre::eyre!("Media {mxc} isn't stored locally")))?
+    } else {
+        with_timeout(
+            config.server_config.upstream_timeout(),
+            "fetching media",
+            fetch_media(&client, &request, config.server_config.media_endpoint),
+        )
+        .await?
+    };
+
+    // Only let the browser display media types that can't run scripts in our
+    // origin. Anything else is downloaded instead.
+    let mimetype = mimetype.filter(|mimetype| !is_thumbnail && is_inline_safe(mimetype));
+    let headers = [
+        (
+            http::header::CONTENT_TYPE,
+            mimetype
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_owned()),
+        ),
+        (
+            http::header::CONTENT_DISPOSITION,
+            if mimetype.is_some() || is_thumbnail {
+                "inline"
+            } else {
+                "attachment"
+            }
+            .to_owned(),
+        ),
+        (http::header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        (
+            http::header::CONTENT_SECURITY_POLICY,
+            "sandbox; default-src 'none'".to_owned(),
+        ),
+    ];
+    Ok((validators, headers, data).into_response())
+}
+
+fn is_inline_safe(mimetype: &str) -> bool {
+    let mimetype = mimetype.to_ascii_lowercase();
+    (mimetype.starts_with("image/") && !mimetype.starts_with("image/svg"))
+        || mimetype.starts_with("video/")
+        || mimetype.starts_with("audio/")
+}
@@ -0,0 +1,461 @@
+//! Prefetching of earlier pages of room history, so that loading earlier
+//! messages doesn't have to wait for the homeserver.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use jiff::Timestamp;
+use matrix_sdk::{
+    Room,
+    deserialized_responses::TimelineEvent,
+    room::{Messages, MessagesOptions},
+    ruma::assign,
+};
+use ruma::{OwnedEventId, OwnedRoomId, RoomId};
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// How long a prefetched page is kept before it's considered stale.
+const PAGE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The most pages kept at once, across all rooms.
+const MAX_PAGES: usize = 256;
+
+/// How long the latest page of a room is reused for pages that look across
+/// many rooms at once.
+const RECENT_TTL: Duration = Duration::from_secs(30);
+
+/// How long where a search through a room's history ended is remembered.
+const LANDMARK_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The most searches through history remembered at once, across all rooms.
+const MAX_LANDMARKS: usize = 1024;
+
+/// The most prefetches run at once. Pages viewed while this many are running
+/// aren't prefetched from.
+const MAX_PREFETCHES: usize = 4;
+
+/// The most events in a page of history.
+const PAGE_SIZE: u8 = 100;
+
+/// The prefix of pagination tokens through the local event cache, which are
+/// followed by the number of the room's newest events that come after them.
+const LOCAL_TOKEN_PREFIX: &str = "local_";
+
+/// A page of room history, as returned by backward pagination.
+///
+/// Pages fetched forward are turned around to match, so `end` is always the
+/// older edge of the page and `start` the newer one.
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// The events in the page, newest first.
+    pub chunk: Vec<TimelineEvent>,
+    /// The token to paginate to the page before this one with.
+    pub end: Option<String>,
+    /// The token to paginate forward to the page after this one with, if
+    /// there is any later history.
+    pub start: Option<String>,
+}
+
+/// A place in a room's history that is found by paginating back to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Landmark {
+    /// The earliest page of the room.
+    Start,
+    /// The first event at or after a time, in milliseconds since the epoch.
+    Date(i64),
+}
+
+/// Where a search for a [`Landmark`] ended.
+#[derive(Clone, Debug)]
+pub struct Position {
+    /// The token of the page it's on, or `None` for the latest page.
+    pub token: Option<String>,
+    /// The event to scroll to on the page, if any.
+    pub event_id: Option<OwnedEventId>,
+}
+
+/// Where the latest page of a room's history comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FetchStrategy {
+    /// Always fetch it from the homeserver with `/messages`
+    #[default]
+    Messages,
+    /// Use the events already received through `/sync` if there are any,
+    /// and only use `/messages` for earlier history
+    CacheFirst,
+}
+
+/// Pages of room history fetched ahead of being requested, keyed by the
+/// pagination token they start from.
+#[derive(Clone, Debug)]
+pub struct PageCache {
+    pages: Arc<Mutex<HashMap<(OwnedRoomId, String), (Instant, Page)>>>,
+    /// The rooms and tokens prefetches are running from, so reloading a page
+    /// doesn't start the same prefetch again.
+    in_flight: Arc<Mutex<HashSet<(OwnedRoomId, String)>>>,
+    prefetch_permits: Arc<Semaphore>,
+    /// The latest events received through sync in each room, for
+    /// [`FetchStrategy::CacheFirst`].
+    synced: Arc<Mutex<HashMap<OwnedRoomId, Page>>>,
+    /// The latest page of each room, as recently fetched by
+    /// [`PageCache::recent_messages`].
+    recent: Arc<Mutex<HashMap<OwnedRoomId, (Instant, Page)>>>,
+    /// Where recent searches through each room's history ended, so repeating
+    /// one doesn't paginate through the room again.
+    landmarks: Arc<Mutex<HashMap<(OwnedRoomId, Landmark), (Instant, Position)>>>,
+    /// When sync last brought events in each room.
+    changed_at: Arc<Mutex<HashMap<OwnedRoomId, Timestamp>>>,
+    /// When the cache was made, which rooms haven't changed since as far as
+    /// it knows.
+    created_at: Timestamp,
+    /// Serve only the events in the local event cache, without contacting
+    /// the homeserver.
+    offline: bool,
+    strategy: FetchStrategy,
+}
+
+impl PageCache {
+    pub fn new(offline: bool, strategy: FetchStrategy) -> Self {
+        Self {
+            pages: Default::default(),
+            in_flight: Default::default(),
+            prefetch_permits: Arc::new(Semaphore::new(MAX_PREFETCHES)),
+            synced: Default::default(),
+            recent: Default::default(),
+            landmarks: Default::default(),
+            changed_at: Default::default(),
+            created_at: Timestamp::now(),
+            offline,
+            strategy,
+        }
+    }
+
+    /// Fetch a page of history from the homeserver, using a prefetched copy
+    /// if there is one.
+    pub async fn messages(&self, room: &Room, from: Option<String>) -> matrix_sdk::Result<Page> {
+        if self.offline {
+            return local_page(room, from).await;
+        }
+        if from.is_none() && self.strategy == FetchStrategy::CacheFirst {
+            if let Some(page) = self.synced_page(room.room_id()) {
+                debug!(
+                    "Using synced events for the latest page of {}",
+                    room.room_id()
+                );
+                return Ok(page);
+            }
+        }
+        if let Some(from) = &from {
+            if let Some(page) = self.take(room.room_id().to_owned(), from.clone()) {
+                debug!("Using prefetched page at {from} in {}", room.room_id());
+                return Ok(page);
+            }
+        }
+        let Messages {
+            chunk, end, start, ..
+        } = room
+            .messages(assign!(MessagesOptions::backward(), {from, limit: PAGE_SIZE.into()}))
+            .await?;
+        Ok(Page {
+            chunk,
+            end,
+            start: Some(start),
+        })
+    }
+
+    /// The latest page of history in a room, reusing one fetched within the
+    /// last [`RECENT_TTL`].
+    ///
+    /// This is for pages that read from every room at once, so reloading
+    /// them doesn't ask the homeserver for every room again.
+    pub async fn recent_messages(&self, room: &Room) -> matrix_sdk::Result<Page> {
+        let room_id = room.room_id().to_owned();
+        {
+            let mut recent = self.recent.lock().unwrap();
+            recent.retain(|_, (fetched, _)| fetched.elapsed() < RECENT_TTL);
+            if let Some((_, page)) = recent.get(&room_id) {
+                return Ok(page.clone());
+            }
+        }
+        let page = self.messages(room, None).await?;
+        self.recent
+            .lock()
+            .unwrap()
+            .insert(room_id, (Instant::now(), page.clone()));
+        Ok(page)
+    }
+
+    /// Fetch the page of history after `from` from the homeserver.
+    ///
+    /// The page's `end` is `from` itself, so paginating back from a page
+    /// fetched forward returns to the page it was reached from.
+    pub async fn messages_after(&self, room: &Room, from: String) -> matrix_sdk::Result<Page> {
+        if self.offline {
+            return local_page_after(room, from).await;
+        }
+        let Messages { mut chunk, end, .. } = room
+            .messages(assign!(MessagesOptions::forward(), {
+                from: Some(from.clone()),
+                limit: PAGE_SIZE.into(),
+            }))
+            .await?;
+        // Forward pagination returns the oldest events first.
+        chunk.reverse();
+        Ok(Page {
+            chunk,
+            end: Some(from),
+            start: end,
+        })
+    }
+
+    /// Fetch up to `pages` pages before `from` in the background, unless a
+    /// prefetch from there is already running or too many are.
+    pub fn prefetch(&self, room: Room, from: String, pages: usize, timeout: Duration) {
+        if self.offline {
+            return;
+        }
+        let Ok(permit) = self.prefetch_permits.clone().try_acquire_owned() else {
+            debug!(
+                "Not prefetching in {}, as too many prefetches are running",
+                room.room_id()
+            );
+            return;
+        };
+        let key = (room.room_id().to_owned(), from.clone());
+        if !self.in_flight.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let cache = self.clone();
+        tokio::spawn(async move {
+            cache.prefetch_pages(&room, from, pages, timeout).await;
+            cache.in_flight.lock().unwrap().remove(&key);
+            drop(permit);
+        });
+    }
+
+    async fn prefetch_pages(&self, room: &Room, mut from: String, pages: usize, timeout: Duration) {
+        let room_id = room.room_id().to_owned();
+        for _ in 0..pages {
+            let key = (room_id.clone(), from.clone());
+            // Pages that are already cached only need to be followed.
+            let end = match self.cached_end(&key) {
+                Some(end) => end,
+                None => {
+                    let options = assign!(MessagesOptions::backward(), {
+                        from: Some(from.clone()),
+                        limit: PAGE_SIZE.into(),
+                    });
+                    match tokio::time::timeout(timeout, room.messages(options)).await {
+                        Ok(Ok(Messages {
+                            chunk, end, start, ..
+                        })) => {
+                            self.insert(
+                                key,
+                                Page {
+                                    chunk,
+                                    end: end.clone(),
+                                    start: Some(start),
+                                },
+                            );
+                            end
+                        }
+                        Ok(Err(err)) => {
+                            warn!("Failed to prefetch history in {room_id}: {err}");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("Timed out prefetching history in {room_id}");
+                            return;
+                        }
+                    }
+                }
+            };
+            match end {
+                Some(end) => from = end,
+                None => return,
+            }
+        }
+    }
+
+    /// Record the timeline of a room from a sync response, given newest last
+    /// as sync returns it, for [`FetchStrategy::CacheFirst`] and
+    /// [`PageCache::changed_at`].
+    ///
+    /// A batch that isn't `limited` follows on from the one before without a
+    /// gap, so it's added to the page while that stays within a page's size.
+    /// Otherwise it replaces the page, so the page never spans a gap and its
+    /// token leads to exactly the events before it.
+    pub fn record_sync(
+        &self,
+        room_id: &RoomId,
+        events: &[TimelineEvent],
+        prev_batch: Option<&str>,
+        limited: bool,
+    ) {
+        if events.is_empty() {
+            return;
+        }
+        self.changed_at
+            .lock()
+            .unwrap()
+            .insert(room_id.to_owned(), Timestamp::now());
+        if self.strategy != FetchStrategy::CacheFirst {
+            return;
+        }
+        let mut batch: Vec<_> = events.iter().rev().cloned().collect();
+        let mut synced = self.synced.lock().unwrap();
+        if let Some(page) = synced.get_mut(room_id) {
+            if !limited && page.chunk.len() + batch.len() <= PAGE_SIZE.into() {
+                batch.append(&mut page.chunk);
+                page.chunk = batch;
+                return;
+            }
+        }
+        match prev_batch {
+            Some(prev_batch) if batch.len() <= PAGE_SIZE.into() => {
+                synced.insert(
+                    room_id.to_owned(),
+                    Page {
+                        chunk: batch,
+                        end: Some(prev_batch.to_owned()),
+                        start: None,
+                    },
+                );
+            }
+            // Without a token to the events before it, or with too many
+            // events, the batch can't be served as a page.
+            _ => {
+                synced.remove(room_id);
+            }
+        }
+    }
+
+    /// When a room's history last changed: when sync last brought events in
+    /// it, or startup if it hasn't since.
+    ///
+    /// Events from the initial sync aren't recorded, but pages aren't served
+    /// until it's done, so no page served can be older than them.
+    pub fn changed_at(&self, room_id: &RoomId) -> Timestamp {
+        self.changed_at
+            .lock()
+            .unwrap()
+            .get(room_id)
+            .copied()
+            .unwrap_or(self.created_at)
+    }
+
+    /// The latest page of a room from the events received through sync, if
+    /// any have been since startup.
+    fn synced_page(&self, room_id: &RoomId) -> Option<Page> {
+        self.synced.lock().unwrap().get(room_id).cloned()
+    }
+
+    /// The `end` token of a cached page, if the page is cached.
+    fn cached_end(&self, key: &(OwnedRoomId, String)) -> Option<Option<String>> {
+        let pages = self.pages.lock().unwrap();
+        pages.get(key).map(|(_, page)| page.end.clone())
+    }
+
+    /// Where a search for `landmark` in a room ended, if it was recent.
+    pub fn landmark(&self, room_id: &RoomId, landmark: Landmark) -> Option<Position> {
+        let landmarks = self.landmarks.lock().unwrap();
+        let (found_at, position) = landmarks.get(&(room_id.to_owned(), landmark))?;
+        (found_at.elapsed() < LANDMARK_TTL).then(|| position.clone())
+    }
+
+    /// Remember where a search for `landmark` in a room ended.
+    pub fn record_landmark(&self, room_id: &RoomId, landmark: Landmark, position: Position) {
+        let mut landmarks = self.landmarks.lock().unwrap();
+        landmarks.retain(|_, (found_at, _)| found_at.elapsed() < LANDMARK_TTL);
+        if landmarks.len() < MAX_LANDMARKS {
+            landmarks.insert((room_id.to_owned(), landmark), (Instant::now(), position));
+        }
+    }
+
+    fn take(&self, room_id: OwnedRoomId, from: String) -> Option<Page> {
+        let mut pages = self.pages.lock().unwrap();
+        let (fetched_at, page) = pages.remove(&(room_id, from))?;
+        (fetched_at.elapsed() < PAGE_TTL).then_some(page)
+    }
+
+    fn insert(&self, key: (OwnedRoomId, String), page: Page) {
+        let mut pages = self.pages.lock().unwrap();
+        pages.retain(|_, (fetched_at, _)| fetched_at.elapsed() < PAGE_TTL);
+        if pages.len() < MAX_PAGES {
+            pages.insert(key, (Instant::now(), page));
+        }
+    }
+}
+
+/// Whether `token` paginates through the local event cache, rather than
+/// being a token from the homeserver.
+pub fn is_local_token(token: &str) -> bool {
+    parse_local_token(token).is_some()
+}
+
+/// The local pagination token `offset` events back from the newest.
+fn local_token(offset: usize) -> String {
+    format!("{LOCAL_TOKEN_PREFIX}{offset}")
+}
+
+fn parse_local_token(token: &str) -> Option<usize> {
+    token.strip_prefix(LOCAL_TOKEN_PREFIX)?.parse().ok()
+}
+
+/// The events in the local event cache, newest first.
+///
+/// Nothing is added to the cache while serving offline, so offsets into it
+/// stay valid as pagination tokens.
+async fn local_events(room: &Room) -> matrix_sdk::Result<Vec<TimelineEvent>> {
+    let (room_cache, _drop_handles) = room
+        .event_cache()
+        .await
+        .map_err(|err| matrix_sdk::Error::UnknownError(err.into()))?;
+    let mut events = room_cache.events().await;
+    events.reverse();
+    Ok(events)
+}
+
+/// A page of the events in the local event cache before `from`, for serving
+/// offline.
+async fn local_page(room: &Room, from: Option<String>) -> matrix_sdk::Result<Page> {
+    let offset = match from {
+        Some(from) => parse_local_token(&from).ok_or_else(|| {
+            matrix_sdk::Error::UnknownError(
+                format!("{from} isn't a pagination token for the local store").into(),
+            )
+        })?,
+        None => 0,
+    };
+    let events = local_events(room).await?;
+    Ok(local_slice(events, offset))
+}
+
+/// A page of the events in the local event cache after `from`, for serving
+/// offline.
+async fn local_page_after(room: &Room, from: String) -> matrix_sdk::Result<Page> {
+    let offset = parse_local_token(&from).ok_or_else(|| {
+        matrix_sdk::Error::UnknownError(
+            format!("{from} isn't a pagination token for the local store").into(),
+        )
+    })?;
+    let events = local_events(room).await?;
+    Ok(local_slice(events, offset.saturating_sub(PAGE_SIZE.into())))
+}
+
+/// The page of `events`, newest first, that starts `offset` events from the
+/// newest, with tokens to the pages on either side of it.
+fn local_slice(events: Vec<TimelineEvent>, offset: usize) -> Page {
+    let total = events.len();
+    let offset = offset.min(total);
+    let end = (offset + usize::from(PAGE_SIZE)).min(total);
+    Page {
+        chunk: events.into_iter().skip(offset).take(end - offset).collect(),
+        end: (end < total).then(|| local_token(end)),
+        start: (offset > 0).then(|| local_token(offset)),
+    }
+}
@@ -0,0 +1,109 @@
+//! Per-client rate limiting, so that a scraper can't overwhelm the instance or
+//! the homeserver behind it.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{self, ConnectInfo, Request},
+    http,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::debug;
+
+/// The most clients tracked at once. Clients whose buckets have refilled are
+/// forgotten first.
+const MAX_CLIENTS: usize = 65536;
+
+/// A token bucket for each client IP address.
+///
+/// Each client can make a minute's worth of requests in a burst, and then
+/// gets tokens back at the configured rate.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    requests_per_minute: NonZeroU32,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: NonZeroU32) -> Self {
+        Self {
+            requests_per_minute,
+            buckets: Default::default(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.requests_per_minute.get().into()
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.capacity() / 60.0
+    }
+
+    /// Take a token for a request from `client`, or return how many seconds
+    /// until one is available.
+    fn check(&self, client: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity());
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity(),
+            updated_at: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.tokens_per_second();
+            Err(wait.ceil() as u64)
+        }
+    }
+
+    /// The tokens in a bucket once it has been refilled up to `now`.
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.tokens_per_second()).min(self.capacity())
+    }
+}
+
+/// Middleware refusing requests from clients that are over the rate limit
+/// with `429 Too Many Requests`.
+///
+/// Clients are told apart by the address of the connection, so behind a
+/// reverse proxy all requests share one bucket.
+pub async fn limit(
+    extract::State(limiter): extract::State<RateLimiter>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(address.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            debug!("Rate limited {} for {}", address.ip(), request.uri());
+            (
+                http::StatusCode::TOO_MANY_REQUESTS,
+                [(http::header::RETRY_AFTER, retry_after.to_string())],
+                "Too many requests, try again later",
+            )
+                .into_response()
+        }
+    }
+}
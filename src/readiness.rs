@@ -0,0 +1,102 @@
+//! Reporting of startup progress, so that operators can tell a long initial
+//! sync from a hung one.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use axum::{
+    extract::{self, Request},
+    http,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::AppState;
+
+/// How far the initial sync has got.
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+    rooms: AtomicUsize,
+    attempts: AtomicUsize,
+    synced: AtomicBool,
+}
+
+impl SyncProgress {
+    /// Record an attempt at the initial sync, and how many rooms are known
+    /// after it.
+    pub fn record_attempt(&self, rooms: usize) {
+        self.rooms.store(rooms, Ordering::Relaxed);
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mark the initial sync as done.
+    pub fn finish(&self, rooms: usize) {
+        self.rooms.store(rooms, Ordering::Relaxed);
+        self.synced.store(true, Ordering::Release);
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Acquire)
+    }
+
+    pub fn rooms(&self) -> usize {
+        self.rooms.load(Ordering::Relaxed)
+    }
+
+    pub fn attempts(&self) -> usize {
+        self.attempts.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The number of rooms synced so far.
+    pub rooms: usize,
+    /// The number of initial sync requests made so far, including failed
+    /// ones.
+    pub sync_attempts: usize,
+}
+
+/// Report whether the initial sync is done, with `503 Service Unavailable`
+/// until it is.
+pub async fn ready(
+    extract::State(AppState { sync_progress, .. }): extract::State<AppState>,
+) -> impl IntoResponse {
+    let ready = sync_progress.is_synced();
+    let status = if ready {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        ready,
+        rooms: sync_progress.rooms(),
+        sync_attempts: sync_progress.attempts(),
+    };
+    (status, axum::Json(readiness))
+}
+
+/// Middleware refusing requests with `503 Service Unavailable` until the
+/// initial sync is done, as pages rendered before then would be incomplete.
+pub async fn require_synced(
+    extract::State(sync_progress): extract::State<Arc<SyncProgress>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if sync_progress.is_synced() {
+        return next.run(request).await;
+    }
+    (
+        http::StatusCode::SERVICE_UNAVAILABLE,
+        [(http::header::RETRY_AFTER, "5")],
+        format!(
+            "Still syncing: {} rooms so far. Try again shortly.",
+            sync_progress.rooms()
+        ),
+    )
+        .into_response()
+}
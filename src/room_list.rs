@@ -1,9 +1,25 @@
 // filepath: /Users/jade/Code/libretto/src/room_list.rs
-use matrix_sdk::{Room, RoomDisplayName, RoomState};
-use ruma::{OwnedRoomId, RoomId};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use matrix_sdk::{
+    Client, Room, RoomDisplayName, RoomState, deserialized_responses::SyncOrStrippedState,
+    sync::RoomUpdates,
+};
+use regex::Regex;
+use ruma::{
+    MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, RoomId,
+    events::{
+        SyncStateEvent,
+        space::{child::SpaceChildEventContent, parent::SpaceParentEventContent},
+    },
+};
 use serde::{Deserialize, Serialize};
 
-use crate::AppError;
+use crate::{AppError, RenderConfig, media::MediaRegistry};
 
 /// Represents a room in the room list with additional metadata
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,7 +30,7 @@ pub struct RoomListEntry {
     /// The human-readable name of the room
     pub name: RoomDisplayName,
 
-    /// The room's avatar URL if available
+    /// The proxied URL of the room's avatar if available
     pub avatar_url: Option<String>,
 
     /// Whether the room is encrypted
@@ -28,6 +44,23 @@ pub struct RoomListEntry {
 
     /// The room's join state (joined, invited, left)
     pub state: RoomState,
+
+    /// When the latest cached event in the room was sent
+    pub last_activity: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// Number of cached events sent on each of the last few days, oldest
+    /// first. Empty if activity isn't being tracked
+    pub daily_activity: Vec<u64>,
+
+    /// The joined spaces the room belongs to, by name
+    pub spaces: Vec<SpaceTag>,
+}
+
+/// A space a room in the room list belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpaceTag {
+    pub id: OwnedRoomId,
+    pub name: String,
 }
 
 impl RoomListEntry {
@@ -40,10 +73,68 @@ impl RoomListEntry {
             .unwrap_or_else(|| "?".to_string())
     }
 
+    /// Check if the room's name was computed from its members, rather than
+    /// set explicitly
+    pub fn has_computed_name(&self) -> bool {
+        crate::room_to_html::is_computed_name(&self.name)
+    }
+
     /// Check if the room has unread messages
     pub fn has_unread(&self) -> bool {
         self.unread_count > 0
     }
+
+    /// Get the points of a sparkline of the room's daily activity, scaled to
+    /// a `SPARKLINE_WIDTH` by `SPARKLINE_HEIGHT` box
+    pub fn sparkline_points(&self) -> Option<String> {
+        if self.daily_activity.len() < 2 {
+            return None;
+        }
+        let max = self
+            .daily_activity
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let step = SPARKLINE_WIDTH as f64 / (self.daily_activity.len() - 1) as f64;
+        let points = self
+            .daily_activity
+            .iter()
+            .enumerate()
+            .map(|(day, count)| {
+                let x = day as f64 * step;
+                let y = SPARKLINE_HEIGHT as f64 * (1.0 - *count as f64 / max as f64);
+                format!("{x:.1},{y:.1}")
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        Some(points)
+    }
+
+    /// Get the total number of events in the tracked days
+    pub fn total_activity(&self) -> u64 {
+        self.daily_activity.iter().sum()
+    }
+}
+
+/// The size of the activity sparkline in the room list
+pub const SPARKLINE_WIDTH: u32 = 100;
+pub const SPARKLINE_HEIGHT: u32 = 20;
+
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// How the room list is sorted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomSort {
+    /// Alphabetically by display name
+    #[default]
+    Name,
+    /// Most recently active first
+    Recent,
+    /// Most unread messages first
+    Unread,
 }
 
 /// A collection of rooms organized by category
@@ -69,6 +160,30 @@ impl RoomList {
         self.rooms.iter().find(|room| room.id == *room_id)
     }
 
+    /// Sort rooms in the given order
+    pub fn sort(&mut self, sort: RoomSort) {
+        match sort {
+            RoomSort::Name => self.sort_by_display_names(),
+            RoomSort::Recent => self.sort_by_recent_activity(),
+            RoomSort::Unread => self.sort_by_unread_count(),
+        }
+    }
+
+    /// Sort rooms by most recent activity, falling back to display names
+    pub fn sort_by_recent_activity(&mut self) {
+        self.sort_by_display_names();
+        // The sort is stable, so rooms with the same activity stay in name order
+        self.rooms
+            .sort_by(|a, b| b.last_activity.cmp(&a.last_activity));
+    }
+
+    /// Sort rooms by unread count, falling back to display names
+    pub fn sort_by_unread_count(&mut self) {
+        self.sort_by_display_names();
+        self.rooms
+            .sort_by(|a, b| b.unread_count.cmp(&a.unread_count));
+    }
+
     /// Sort rooms by display names alphabetically
     pub fn sort_by_display_names(&mut self) {
         self.rooms.sort_by(|a, b| {
@@ -82,18 +197,286 @@ impl RoomList {
     }
 }
 
+/// Room list entries kept between loads of the room list, so rooms that
+/// haven't changed don't have their metadata looked up again
+///
+/// Entries are dropped when sync brings changes to their room, and expire
+/// after a while regardless so activity sparklines keep moving in quiet
+/// rooms. Rooms left out of the room list are cached as `None`
+#[derive(Clone, Debug, Default)]
+pub struct RoomListCache {
+    entries: Arc<Mutex<HashMap<OwnedRoomId, (Instant, Option<RoomListEntry>)>>>,
+    /// The joined spaces each room belongs to, from [`space_memberships`].
+    /// Dropped when sync brings changes to any space relation or name
+    spaces: Arc<Mutex<Option<(Instant, HashMap<OwnedRoomId, Vec<SpaceTag>>)>>>,
+    /// How long entries are kept. Zero disables the cache
+    ttl: Duration,
+}
+
+impl RoomListCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            ..Default::default()
+        }
+    }
+
+    /// Get a room's cached entry, if it hasn't expired
+    pub fn get(&self, room_id: &RoomId) -> Option<Option<RoomListEntry>> {
+        let entries = self.entries.lock().unwrap();
+        let (cached_at, entry) = entries.get(room_id)?;
+        (cached_at.elapsed() < self.ttl).then(|| entry.clone())
+    }
+
+    pub fn insert(&self, room_id: OwnedRoomId, entry: Option<RoomListEntry>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(room_id, (Instant::now(), entry));
+    }
+
+    /// Drop the entries of rooms that have changed
+    pub fn invalidate<'a>(&self, room_ids: impl IntoIterator<Item = &'a OwnedRoomId>) {
+        let mut entries = self.entries.lock().unwrap();
+        for room_id in room_ids {
+            entries.remove(room_id);
+        }
+    }
+
+    /// Get the joined spaces each room belongs to, looking them up again if
+    /// they have changed or expired
+    pub async fn space_memberships(&self, client: &Client) -> HashMap<OwnedRoomId, Vec<SpaceTag>> {
+        let cached = self
+            .spaces
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(cached_at, _)| cached_at.elapsed() < self.ttl)
+            .map(|(_, memberships)| memberships.clone());
+        if let Some(memberships) = cached {
+            return memberships;
+        }
+        let memberships = space_memberships(client).await;
+        if !self.ttl.is_zero() {
+            *self.spaces.lock().unwrap() = Some((Instant::now(), memberships.clone()));
+        }
+        memberships
+    }
+
+    /// Drop the cached space memberships if a sync response may have changed
+    /// them: a space relation or name changed, or a room was left
+    pub fn invalidate_spaces(&self, rooms: &RoomUpdates) {
+        let changed = !rooms.leave.is_empty()
+            || rooms.join.values().any(|update| {
+                let state_types = update
+                    .state
+                    .iter()
+                    .map(|event| event.get_field::<String>("type"));
+                let timeline_types = update
+                    .timeline
+                    .events
+                    .iter()
+                    .map(|event| event.raw().get_field::<String>("type"));
+                state_types
+                    .chain(timeline_types)
+                    .any(|event_type| match event_type {
+                        Ok(Some(event_type)) => SPACE_EVENT_TYPES.contains(&event_type.as_str()),
+                        _ => false,
+                    })
+            });
+        if changed {
+            *self.spaces.lock().unwrap() = None;
+        }
+    }
+}
+
+/// The types of events that can change which spaces rooms are shown in, or
+/// the names those spaces are shown with
+const SPACE_EVENT_TYPES: &[&str] = &[
+    "m.space.child",
+    "m.space.parent",
+    "m.room.name",
+    "m.room.canonical_alias",
+];
+
+/// Check if a room is plumbing rather than a community, and should be left
+/// out of the room list: a room with only a configured bot in it besides us,
+/// or a room with an alias matching the configured pattern
+pub async fn is_utility_room(
+    room: &Room,
+    bots: &[OwnedUserId],
+    alias_pattern: Option<&Regex>,
+) -> bool {
+    if !bots.is_empty() && room.joined_members_count() <= 2 {
+        if let Ok(members) = room.joined_user_ids().await {
+            if members.iter().any(|member| bots.contains(member)) {
+                return true;
+            }
+        }
+    }
+
+    if let Some(alias_pattern) = alias_pattern {
+        let mut aliases = room.canonical_alias().into_iter().chain(room.alt_aliases());
+        if aliases.any(|alias| alias_pattern.is_match(alias.as_str())) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Map each room to the joined spaces it belongs to, going by both the
+/// spaces' `m.space.child` events and the rooms' `m.space.parent` events
+///
+/// Spaces that aren't joined are left out, as their names aren't known
+async fn space_memberships(client: &Client) -> HashMap<OwnedRoomId, Vec<SpaceTag>> {
+    let rooms = client.joined_rooms();
+    let mut space_names = HashMap::new();
+    for room in rooms.iter().filter(|room| room.is_space()) {
+        let name = match room.display_name().await {
+            Ok(name) => name.to_string(),
+            Err(_) => room.room_id().to_string(),
+        };
+        space_names.insert(room.room_id().to_owned(), name);
+    }
+
+    let mut memberships: HashMap<OwnedRoomId, Vec<SpaceTag>> = HashMap::new();
+    let mut add = |room_id: OwnedRoomId, space_id: &RoomId| {
+        let Some(name) = space_names.get(space_id) else {
+            return;
+        };
+        let tag = SpaceTag {
+            id: space_id.to_owned(),
+            name: name.clone(),
+        };
+        let spaces = memberships.entry(room_id).or_default();
+        if !spaces.contains(&tag) {
+            spaces.push(tag);
+        }
+    };
+    for room in &rooms {
+        if room.is_space() {
+            let children = room
+                .get_state_events_static::<SpaceChildEventContent>()
+                .await
+                .unwrap_or_default();
+            for child in children {
+                if let Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(child))) =
+                    child.deserialize()
+                {
+                    // An event with no `via` servers is a removed relation.
+                    if !child.content.via.is_empty() {
+                        add(child.state_key, room.room_id());
+                    }
+                }
+            }
+        }
+        let parents = room
+            .get_state_events_static::<SpaceParentEventContent>()
+            .await
+            .unwrap_or_default();
+        for parent in parents {
+            if let Ok(SyncOrStrippedState::Sync(SyncStateEvent::Original(parent))) =
+                parent.deserialize()
+            {
+                if !parent.content.via.is_empty() {
+                    add(room.room_id().to_owned(), &parent.state_key);
+                }
+            }
+        }
+    }
+    for spaces in memberships.values_mut() {
+        spaces.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+    }
+    memberships
+}
+
+/// Get a room's name without computing one from its members: its explicit
+/// name, canonical alias, or else its ID
+///
+/// The ID is given as a set name, so it isn't styled like a name computed
+/// from the room's members
+fn explicit_name(room: &Room) -> RoomDisplayName {
+    if let Some(name) = room.name().filter(|name| !name.is_empty()) {
+        RoomDisplayName::Named(name)
+    } else if let Some(alias) = room.canonical_alias() {
+        RoomDisplayName::Aliased(alias.alias().to_owned())
+    } else {
+        RoomDisplayName::Named(room.room_id().to_string())
+    }
+}
+
+/// Count events per day over the last `days` days, oldest first
+fn daily_activity(timestamps: &[MilliSecondsSinceUnixEpoch], days: u16) -> Vec<u64> {
+    let mut counts = vec![0; days.into()];
+    let now = u64::from(MilliSecondsSinceUnixEpoch::now().get());
+    for timestamp in timestamps {
+        let age_in_days = now.saturating_sub(timestamp.get().into()) / MILLIS_PER_DAY;
+        if let Ok(age_in_days) = usize::try_from(age_in_days) {
+            if age_in_days < counts.len() {
+                let index = counts.len() - 1 - age_in_days;
+                counts[index] += 1;
+            }
+        }
+    }
+    counts
+}
+
 /// Helper function to create a RoomListEntry from a matrix-sdk Room
-pub async fn room_to_list_entry(room: &Room) -> Result<RoomListEntry, AppError> {
+///
+/// If `render_config.activity_days` is non-zero, the room's activity over that
+/// many days is counted from the event cache.
+pub async fn room_to_list_entry(
+    room: &Room,
+    media: &MediaRegistry,
+    render_config: &RenderConfig,
+) -> Result<RoomListEntry, AppError> {
+    let activity_days = render_config.activity_days;
+    let avatar_size = render_config.list_avatar_size;
     let room_id = room.room_id().to_owned();
     let is_direct = room.is_direct().await?;
+    let timestamps: Vec<MilliSecondsSinceUnixEpoch> = match room.event_cache().await {
+        Ok((room_cache, _drop_handles)) => room_cache
+            .events()
+            .await
+            .iter()
+            .filter_map(|event| {
+                event
+                    .raw()
+                    .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                    .ok()
+                    .flatten()
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let last_activity = timestamps.last().copied();
+    let daily_activity = if activity_days > 0 {
+        daily_activity(&timestamps, activity_days)
+    } else {
+        Vec::new()
+    };
+
+    let large_room_members = render_config.large_room_members;
+    let name = if large_room_members > 0 && room.joined_members_count() > large_room_members {
+        explicit_name(room)
+    } else {
+        room.display_name().await?
+    };
 
     Ok(RoomListEntry {
         id: room_id,
-        name: room.display_name().await?,
-        avatar_url: room.avatar_url().map(|url| url.to_string()),
+        name,
+        avatar_url: room
+            .avatar_url()
+            .and_then(|url| media.mxc_thumbnail_url(&url, avatar_size, avatar_size)),
         is_encrypted: room.encryption_state().is_encrypted(),
         is_direct,
         unread_count: room.unread_notification_counts().notification_count,
         state: room.state(),
+        last_activity,
+        daily_activity,
+        spaces: Vec::new(),
     })
 }
@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use color_eyre::eyre;
+use matrix_sdk::{Room, RoomMemberships, deserialized_responses::RawSyncOrStrippedState};
+use ruma::{
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId,
+    events::{
+        AnyStateEvent, StateEvent, SyncStateEvent,
+        room::member::{MembershipState, RoomMemberEventContent},
+    },
+    serde::Raw,
+};
+use tracing::warn;
+
+/// A read-only snapshot of a room's resolved state.
+#[derive(Debug, Clone, Default)]
+pub struct RoomStateSnapshot {
+    /// The room's name, if it has one.
+    pub name: Option<String>,
+
+    /// The room's topic, if it has one.
+    pub topic: Option<String>,
+
+    /// The joined and invited members of the room.
+    pub members: Vec<StateMember>,
+
+    /// The users with an explicit power level, highest first.
+    pub power_levels: Vec<(OwnedUserId, i64)>,
+}
+
+/// A member of a room in a [`RoomStateSnapshot`].
+#[derive(Debug, Clone)]
+pub struct StateMember {
+    pub user_id: OwnedUserId,
+    pub display_name: Option<String>,
+    pub membership: MembershipState,
+}
+
+impl RoomStateSnapshot {
+    /// Resolve a snapshot from a full set of state events.
+    fn from_state_events(events: &[Raw<AnyStateEvent>]) -> Self {
+        let mut snapshot = Self::default();
+        for event in events {
+            let event = match event.deserialize() {
+                Ok(event) => event,
+                Err(err) => {
+                    warn!("Skipping state event that failed to deserialize: {err}");
+                    continue;
+                }
+            };
+            match event {
+                AnyStateEvent::RoomName(StateEvent::Original(event)) => {
+                    snapshot.name = Some(event.content.name);
+                }
+                AnyStateEvent::RoomTopic(StateEvent::Original(event)) => {
+                    snapshot.topic = Some(event.content.topic);
+                }
+                AnyStateEvent::RoomMember(StateEvent::Original(event)) => {
+                    if matches!(
+                        event.content.membership,
+                        MembershipState::Join | MembershipState::Invite
+                    ) {
+                        snapshot.members.push(StateMember {
+                            user_id: event.state_key,
+                            display_name: event.content.displayname,
+                            membership: event.content.membership,
+                        });
+                    }
+                }
+                AnyStateEvent::RoomPowerLevels(StateEvent::Original(event)) => {
+                    snapshot.power_levels = event
+                        .content
+                        .users
+                        .into_iter()
+                        .map(|(user_id, level)| (user_id, level.into()))
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        snapshot.sort();
+        snapshot
+    }
+
+    fn sort(&mut self) {
+        self.members.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        self.power_levels
+            .sort_by(|(a_user, a_level), (b_user, b_level)| {
+                b_level.cmp(a_level).then_with(|| a_user.cmp(b_user))
+            });
+    }
+}
+
+/// Fetch the state of a room as it was at `event_id`.
+///
+/// This uses the state returned alongside the event's context, which is the
+/// state of the room at that event.
+pub async fn state_at_event(
+    room: &Room,
+    event_id: &EventId,
+    timeout: Duration,
+) -> eyre::Result<RoomStateSnapshot> {
+    let response = tokio::time::timeout(
+        timeout,
+        room.event_with_context(event_id, false, 0u8.into(), None),
+    )
+    .await
+    .map_err(|_| eyre::eyre!("Timed out fetching the state at {event_id}"))??;
+    if response.state.is_empty() {
+        eyre::bail!("The homeserver returned no state at {event_id}");
+    }
+    Ok(RoomStateSnapshot::from_state_events(&response.state))
+}
+
+/// How many of our own earlier membership events are followed back to find
+/// when we joined a room, past profile changes.
+const MAX_MEMBERSHIP_HISTORY: usize = 8;
+
+/// When our account joined a room, found by following our membership events
+/// back through `replaces_state` past any profile changes.
+///
+/// This is `None` if the join can't be found, such as when it is further
+/// back than [`MAX_MEMBERSHIP_HISTORY`] events or can't be fetched in time.
+/// Offline, only the current membership event is looked at.
+pub async fn joined_at(
+    room: &Room,
+    timeout: Duration,
+    offline: bool,
+) -> Option<MilliSecondsSinceUnixEpoch> {
+    let user_id = room.client().user_id()?.to_owned();
+    let event = room
+        .get_state_event_static_for_key::<RoomMemberEventContent, _>(&user_id)
+        .await
+        .ok()??;
+    let RawSyncOrStrippedState::Sync(mut raw) = event else {
+        return None;
+    };
+    for _ in 0..=MAX_MEMBERSHIP_HISTORY {
+        let SyncStateEvent::Original(event) = raw.deserialize().ok()? else {
+            return None;
+        };
+        if event.content.membership != MembershipState::Join {
+            return None;
+        }
+        let was_joined = event
+            .unsigned
+            .prev_content
+            .is_some_and(|prev| prev.membership == MembershipState::Join);
+        if !was_joined {
+            return Some(event.origin_server_ts);
+        }
+        if offline {
+            return None;
+        }
+        let previous = replaces_state(&raw)?;
+        let previous = tokio::time::timeout(timeout, room.event(&previous, None))
+            .await
+            .ok()?
+            .inspect_err(|err| warn!("Failed to fetch membership event {previous}: {err}"))
+            .ok()?;
+        raw = previous.raw().clone().cast();
+    }
+    None
+}
+
+/// The event a state event replaced, from its unsigned data.
+fn replaces_state<T>(raw: &Raw<T>) -> Option<OwnedEventId> {
+    let unsigned = raw.get_field::<serde_json::Value>("unsigned").ok()??;
+    unsigned.get("replaces_state")?.as_str()?.try_into().ok()
+}
+
+/// The timestamp of the newest event received for a room through sync, to
+/// show how current the archive of it is.
+pub async fn last_synced_at(room: &Room) -> Option<MilliSecondsSinceUnixEpoch> {
+    let (room_cache, _drop_handles) = room.event_cache().await.ok()?;
+    let events = room_cache.events().await;
+    events.iter().rev().find_map(|event| {
+        event
+            .raw()
+            .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+            .ok()
+            .flatten()
+    })
+}
+
+/// Build a snapshot of the room's current state from the local store.
+pub async fn current_state(room: &Room) -> eyre::Result<RoomStateSnapshot> {
+    let members = room
+        .members(RoomMemberships::JOIN | RoomMemberships::INVITE)
+        .await?
+        .into_iter()
+        .map(|member| StateMember {
+            user_id: member.user_id().to_owned(),
+            display_name: member.display_name().map(ToOwned::to_owned),
+            membership: member.membership().clone(),
+        })
+        .collect();
+    let power_levels = room
+        .power_levels()
+        .await?
+        .users
+        .into_iter()
+        .map(|(user_id, level)| (user_id, level.into()))
+        .collect();
+
+    let mut snapshot = RoomStateSnapshot {
+        name: room.name(),
+        topic: room.topic(),
+        members,
+        power_levels,
+    };
+    snapshot.sort();
+    Ok(snapshot)
+}
@@ -1,25 +1,579 @@
-use icu::{calendar::Gregorian, datetime::TypedDateTimeFormatter, locid::locale};
-use jiff::Timestamp;
-use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
-use ruma::events::room::message::{FormattedBody, MessageType};
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use crate::timeline::{MsgLikeKind, TimelineEvent, TimelineItemContent};
+use color_eyre::eyre::{self, Context};
+use icu::{
+    calendar::Gregorian,
+    datetime::{TimeFormatter, TypedDateFormatter, TypedDateTimeFormatter, options::length},
+    locid::Locale,
+};
+use jiff::{Timestamp, tz::TimeZone};
+use matrix_sdk::{RoomDisplayName, ruma::MilliSecondsSinceUnixEpoch};
+use ruma::{
+    EventId, MxcUri, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId, RoomId,
+    events::{
+        AnyFullStateEventContent, FullStateEventContent,
+        room::{
+            MediaSource,
+            join_rules::{AllowRule, JoinRule},
+            member::MembershipState,
+            message::{FormattedBody, MessageType},
+            server_acl::RoomServerAclEventContent,
+            tombstone::RoomTombstoneEventContent,
+        },
+    },
+    html::{ElementData, Html, NodeRef},
+};
+use serde_json::value::RawValue;
+use tracing::warn;
+
+use crate::{
+    media::MediaRegistry,
+    room_state::RoomStateSnapshot,
+    timeline::{MsgLikeContent, MsgLikeKind, OtherState, TimelineEvent, TimelineItemContent},
+};
+
+/// Options from the server configuration that affect every page.
+#[derive(Clone, Copy, Debug)]
+pub struct Appearance {
+    /// Whether pages link to the stylesheet from `--custom-css`.
+    pub custom_css: bool,
+    /// Whether emoji are rendered with the font from `--emoji-font`.
+    pub emoji_font: bool,
+    /// How many distinct reactions are shown on a message before the rest
+    /// are collapsed. 0 shows them all.
+    pub max_reactions: usize,
+}
 
 #[derive(askama::Template)]
 #[template(path = "room_list.html.j2")]
 pub struct RoomListTemplate {
+    pub appearance: Appearance,
     pub rooms: Vec<crate::room_list::RoomListEntry>,
+    /// The account's user ID, so an admin knows who to invite to rooms.
+    pub user_id: Option<OwnedUserId>,
+    /// Whether the account has joined rooms that were all hidden by the
+    /// configured filters.
+    pub all_hidden: bool,
+}
+
+impl RoomListTemplate {
+    /// The number of rooms with unread messages.
+    pub fn unread_rooms(&self) -> usize {
+        self.rooms.iter().filter(|room| room.has_unread()).count()
+    }
 }
 
 #[derive(askama::Template)]
 #[template(path = "room.html.j2")]
 pub struct RoomTemplate<'a> {
+    pub appearance: Appearance,
+    /// The scheme and host of the archive, for the links on printed pages,
+    /// or empty to print relative links.
+    pub base_url: String,
     pub room_id: &'a matrix_sdk::ruma::RoomId,
-    pub name: String,
-    pub events: Vec<TimelineEvent>,
+    pub name: RoomDisplayName,
+    pub events: Vec<TimelineItem>,
+    pub time_format: TimeFormat,
     pub hit_end_of_timeline: bool,
+    /// Whether the room's create event is in this page, so there is no
+    /// earlier history.
+    pub reached_room_start: bool,
+    /// Whether this page shows the most recent messages in the room.
+    pub is_latest: bool,
+    /// The user whose mentions this page is limited to, if any.
+    pub mentions_filter: Option<OwnedUserId>,
+    /// The message types this page is limited to. Empty shows every event.
+    pub types_filter: Vec<&'static str>,
+    /// The proxied URL of a thumbnail of the room's avatar.
+    pub avatar_url: Option<String>,
+    /// The token to paginate to earlier history with, if there is any.
+    pub prev_batch: Option<String>,
+    /// The token to paginate forward to later history with, for pages of
+    /// earlier history that aren't up to the present.
+    pub next_batch: Option<String>,
+    /// The query string of the request, to carry over to pagination links.
+    pub query_suffix: String,
+    pub order: MessageOrder,
     pub room: &'a matrix_sdk::room::Room,
+    /// The room that replaced this one, if it has been upgraded.
+    pub tombstone: Option<RoomTombstoneEventContent>,
+    /// The room this one replaced, if it is the result of an upgrade.
+    pub predecessor: Option<OwnedRoomId>,
+    /// Whether this page shows a single thread, rather than the room.
+    pub is_thread_view: bool,
+    pub media: MediaRegistry,
+    /// Whether timestamps and stream order disagree in this page.
+    pub order_discrepancy: bool,
+    /// The number of messages on this page, if none of them could be
+    /// decrypted and they are collapsed behind a single notice.
+    pub collapsed_undecryptable: Option<usize>,
+    /// Whether any messages on this page were sent before our account joined
+    /// the room.
+    pub has_pre_join_events: bool,
+    /// When the newest event synced for the room was sent, to show how
+    /// current the archive is.
+    pub synced_at: Option<MilliSecondsSinceUnixEpoch>,
+    pub event_sort: EventSort,
+    pub join_rule: JoinRule,
+    /// The IDs and names of the rooms whose members can join, for restricted
+    /// rooms.
+    pub join_rule_spaces: Vec<(OwnedRoomId, Option<String>)>,
+    /// The room's canonical alias, shown as a shareable link. Navigation
+    /// within the archive links by room ID, so it needs no directory lookup
+    /// and can't follow an alias that has moved to another room.
+    pub canonical_alias: Option<OwnedRoomAliasId>,
+    pub density: Density,
+    /// The widest the timeline is laid out, in `em`.
+    pub content_width: u16,
+}
+
+#[derive(askama::Template)]
+#[template(path = "thread.html.j2")]
+pub struct ThreadTemplate<'a> {
+    pub appearance: Appearance,
+    /// The scheme and host of the archive, for the links on printed pages,
+    /// or empty to print relative links.
+    pub base_url: String,
+    pub room_id: &'a matrix_sdk::ruma::RoomId,
+    pub thread_root: &'a EventId,
+    pub name: String,
+    /// The thread root, followed by the messages in the thread.
+    pub events: Vec<TimelineItem>,
+    /// The token to continue the thread from, if it was cut off.
+    pub next_batch: Option<String>,
+    /// The query string of the request, to carry over to the continue link.
+    pub query_suffix: String,
+    pub time_format: TimeFormat,
+    /// Whether this page shows a single thread, rather than the room.
+    pub is_thread_view: bool,
+    pub media: MediaRegistry,
+}
+
+#[derive(askama::Template)]
+#[template(path = "room_state.html.j2")]
+pub struct RoomStateTemplate<'a> {
+    pub appearance: Appearance,
+    pub room_id: &'a matrix_sdk::ruma::RoomId,
+    pub name: String,
+    /// The event the state was requested at, if any.
+    pub at: Option<OwnedEventId>,
+    /// Whether `snapshot` is the state at `at`, rather than the current state.
+    pub is_historical: bool,
+    pub snapshot: RoomStateSnapshot,
+}
+
+/// A user's recent messages, grouped by room.
+#[derive(askama::Template)]
+#[template(path = "user.html.j2")]
+pub struct UserTemplate<'a> {
+    pub appearance: Appearance,
+    /// The scheme and host of the archive, for the links on printed pages,
+    /// or empty to print relative links.
+    pub base_url: String,
+    pub user_id: &'a ruma::UserId,
+    /// Each room's ID, name and the user's recent messages in it, most
+    /// recently active first.
+    pub rooms: Vec<(OwnedRoomId, String, Vec<TimelineItem>)>,
+    pub time_format: TimeFormat,
+    /// Always false, since messages here are shown outside their threads;
+    /// the shared message template checks it.
+    pub is_thread_view: bool,
+    pub media: MediaRegistry,
+}
+
+/// An index of the media sent in part of a room's history.
+#[derive(askama::Template)]
+#[template(path = "media_gallery.html.j2")]
+pub struct MediaGalleryTemplate<'a> {
+    pub appearance: Appearance,
+    pub room_id: &'a matrix_sdk::ruma::RoomId,
+    pub name: String,
+    /// The media messages found, newest first.
+    pub items: Vec<GalleryItem>,
+    /// The token to continue the gallery from with older media, if there is
+    /// more history.
+    pub next_from: Option<String>,
+    pub time_format: TimeFormat,
+    pub media: MediaRegistry,
+}
+
+/// A media message in a [`MediaGalleryTemplate`].
+pub struct GalleryItem {
+    /// The pagination token of the room page the message is on, or `None` if
+    /// it is on the latest page.
+    pub page: Option<String>,
+    pub event: TimelineEvent,
+}
+
+#[derive(askama::Template)]
+#[template(path = "error.html.j2")]
+pub struct ErrorTemplate {
+    pub appearance: Appearance,
+    pub status: axum::http::StatusCode,
+    /// What went wrong, in terms a viewer can act on.
+    pub message: &'static str,
+    /// The full error, only shown when verbose errors are enabled.
+    pub detail: Option<String>,
+    /// Whether the error is from the archive being served offline.
+    pub offline: bool,
+}
+
+/// Shown in place of a page of history whose pagination token has expired.
+#[derive(askama::Template)]
+#[template(path = "expired_link.html.j2")]
+pub struct ExpiredLinkTemplate<'a> {
+    pub appearance: Appearance,
+    pub room_id: &'a matrix_sdk::ruma::RoomId,
+    /// The query string of the request, to carry over to the room link.
+    pub query_suffix: String,
+}
+
+/// The order messages are displayed in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageOrder {
+    /// Oldest messages first.
+    #[default]
+    Asc,
+    /// Newest messages first.
+    Desc,
+}
+
+/// How tightly the timeline is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Density {
+    /// Smaller text and avatars with little spacing, to fit more messages on
+    /// screen.
+    Compact,
+    #[default]
+    Comfortable,
+    /// Larger text with more spacing, for reading.
+    Large,
+}
+
+impl Density {
+    /// The class applied to the page for this density.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Compact => "density-compact",
+            Self::Comfortable => "density-comfortable",
+            Self::Large => "density-large",
+        }
+    }
+}
+
+/// What rendered events are ordered by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EventSort {
+    /// The order the homeserver received events in, which is canonical.
+    #[default]
+    Stream,
+    /// The timestamps events were sent with.
+    Timestamp,
+}
+
+/// The number of messages in a page if every one of them failed to decrypt,
+/// or `None` if any message could be read.
+pub fn undecryptable_count(events: &[TimelineEvent]) -> Option<usize> {
+    let mut count = 0;
+    for event in events {
+        if let TimelineItemContent::MsgLike(msg_like) = &event.content {
+            match msg_like.kind {
+                MsgLikeKind::UnableToDecrypt => count += 1,
+                MsgLikeKind::Hidden => {}
+                _ => return None,
+            }
+        }
+    }
+    (count > 0).then_some(count)
+}
+
+/// How far back in time an event has to be from the one before it in stream
+/// order for the two orders to be considered to disagree.
+const ORDER_DISCREPANCY: Duration = Duration::from_secs(60);
+
+/// Whether the timestamps of events in stream order go backwards by a
+/// significant amount anywhere, e.g. because of federation delays.
+pub fn has_order_discrepancy(events: &[TimelineEvent], order: MessageOrder) -> bool {
+    events.windows(2).any(|pair| {
+        let (earlier, later) = match order {
+            MessageOrder::Asc => (&pair[0], &pair[1]),
+            MessageOrder::Desc => (&pair[1], &pair[0]),
+        };
+        u64::from(earlier.timestamp.0).saturating_sub(u64::from(later.timestamp.0))
+            > ORDER_DISCREPANCY.as_millis() as u64
+    })
+}
+
+/// Sort events by their timestamps, keeping stream order for equal
+/// timestamps.
+pub fn sort_by_timestamp(events: &mut [TimelineEvent], order: MessageOrder) {
+    match order {
+        MessageOrder::Asc => events.sort_by_key(|event| event.timestamp),
+        MessageOrder::Desc => events.sort_by(|a, b| b.timestamp.cmp(&a.timestamp)),
+    }
+}
+
+/// An entry in the rendered timeline.
+#[derive(Clone, Debug)]
+pub enum TimelineItem {
+    /// Marks the first event of a new day.
+    DayDivider(MilliSecondsSinceUnixEpoch),
+    /// An event, and whether it continues the message group of the event
+    /// before it.
+    Event {
+        event: TimelineEvent,
+        continuation: bool,
+    },
+    /// Consecutive images in one message group, laid out as a grid, each
+    /// with whether it continues the message group before it.
+    ImageSet(Vec<(TimelineEvent, bool)>),
+}
+
+/// Group consecutive messages from the same sender sent within `window` of
+/// each other, and insert day dividers where the day changes. Runs of images
+/// within a group are collected into image sets.
+///
+/// `events` may be in either order; groups and day dividers always start at
+/// the first event displayed. A zero `window` disables grouping.
+pub fn group_timeline(
+    events: Vec<TimelineEvent>,
+    window: Duration,
+    time_format: &TimeFormat,
+) -> Vec<TimelineItem> {
+    let mut items: Vec<TimelineItem> = Vec::with_capacity(events.len());
+
+    for mut event in events {
+        let previous = items.iter().rev().find_map(|item| match item {
+            TimelineItem::Event { event, .. } => Some(event),
+            TimelineItem::ImageSet(events) => events.last().map(|(event, _)| event),
+            TimelineItem::DayDivider(_) => None,
+        });
+        let new_day = previous.is_none_or(|previous| {
+            time_format.day(&previous.timestamp) != time_format.day(&event.timestamp)
+        });
+        let continuation =
+            !new_day && previous.is_some_and(|previous| continues_group(previous, &event, window));
+
+        if continuation {
+            let previous_id = previous.and_then(|previous| previous.event_id.clone());
+            if let TimelineItemContent::MsgLike(MsgLikeContent {
+                in_reply_to: Some(in_reply_to),
+                ..
+            }) = &mut event.content
+            {
+                in_reply_to.replies_to_previous =
+                    previous_id.as_ref() == Some(&in_reply_to.event_id);
+            }
+        }
+
+        if new_day {
+            items.push(TimelineItem::DayDivider(event.timestamp));
+        }
+        items.push(TimelineItem::Event {
+            event,
+            continuation,
+        });
+    }
+
+    collect_image_sets(items)
+}
+
+/// Replace runs of at least two image messages, each continuing the message
+/// group of the one before, with image sets.
+fn collect_image_sets(items: Vec<TimelineItem>) -> Vec<TimelineItem> {
+    fn flush(collected: &mut Vec<TimelineItem>, run: &mut Vec<(TimelineEvent, bool)>) {
+        if run.len() >= 2 {
+            collected.push(TimelineItem::ImageSet(std::mem::take(run)));
+        } else {
+            collected.extend(
+                run.drain(..)
+                    .map(|(event, continuation)| TimelineItem::Event {
+                        event,
+                        continuation,
+                    }),
+            );
+        }
+    }
+
+    let mut collected = Vec::with_capacity(items.len());
+    let mut run = Vec::new();
+    for item in items {
+        match item {
+            TimelineItem::Event {
+                event,
+                continuation,
+            } if is_image_message(&event) => {
+                if !continuation {
+                    flush(&mut collected, &mut run);
+                }
+                run.push((event, continuation));
+            }
+            item => {
+                flush(&mut collected, &mut run);
+                collected.push(item);
+            }
+        }
+    }
+    flush(&mut collected, &mut run);
+    collected
+}
+
+/// Whether an event is an image message that can be shown in an image set,
+/// which leaves out replies so that their quotes aren't squashed.
+fn is_image_message(event: &TimelineEvent) -> bool {
+    matches!(
+        &event.content,
+        TimelineItemContent::MsgLike(MsgLikeContent {
+            kind: MsgLikeKind::Message(message),
+            in_reply_to: None,
+            ..
+        }) if matches!(message.msgtype, MessageType::Image(_))
+    )
+}
+
+fn continues_group(previous: &TimelineEvent, event: &TimelineEvent, window: Duration) -> bool {
+    let is_message = |event: &TimelineEvent| match &event.content {
+        TimelineItemContent::MsgLike(msg_like) => !matches!(msg_like.kind, MsgLikeKind::Hidden),
+        _ => false,
+    };
+    let gap = u64::from(event.timestamp.0).abs_diff(u64::from(previous.timestamp.0));
+
+    !window.is_zero()
+        && previous.sender == event.sender
+        && previous.bridged_sender == event.bridged_sender
+        && is_message(previous)
+        && is_message(event)
+        && u128::from(gap) <= window.as_millis()
+}
+
+/// The locale and time zone timestamps are displayed in.
+#[derive(Clone, Debug)]
+pub struct TimeFormat {
+    pub locale: Locale,
+    pub time_zone: TimeZone,
+    /// Whether timestamps end with the time zone's abbreviation, so they are
+    /// unambiguous.
+    pub show_time_zone: bool,
+}
+
+impl TimeFormat {
+    pub fn new(locale: &str, time_zone: &str, show_time_zone: bool) -> eyre::Result<Self> {
+        Ok(Self {
+            locale: locale
+                .parse()
+                .map_err(|err| eyre::eyre!("Invalid locale {locale}: {err}"))?,
+            time_zone: TimeZone::get(time_zone)
+                .wrap_err_with(|| format!("Invalid time zone {time_zone}"))?,
+            show_time_zone,
+        })
+    }
+
+    /// Override the locale and time zone with a viewer's preferences, taken
+    /// from an `Accept-Language` header and an IANA time zone name. Invalid
+    /// preferences are ignored.
+    pub fn for_viewer(&self, accept_language: Option<&str>, time_zone: Option<&str>) -> Self {
+        Self {
+            locale: accept_language
+                .and_then(preferred_locale)
+                .unwrap_or_else(|| self.locale.clone()),
+            time_zone: time_zone
+                .and_then(|time_zone| TimeZone::get(time_zone).ok())
+                .unwrap_or_else(|| self.time_zone.clone()),
+            show_time_zone: self.show_time_zone,
+        }
+    }
+
+    pub fn day(&self, ts: &MilliSecondsSinceUnixEpoch) -> Option<jiff::civil::Date> {
+        Timestamp::from_millisecond(ts.0.into())
+            .ok()
+            .map(|ts| ts.to_zoned(self.time_zone.clone()).date())
+    }
+
+    pub fn format_timestamp(&self, ts: &MilliSecondsSinceUnixEpoch) -> String {
+        let formatted = milliseconds_since_unix_epoch_to_format_string(
+            ts.0.into(),
+            &self.locale,
+            &self.time_zone,
+        );
+        self.with_time_zone(formatted, ts)
+    }
+
+    /// Append the abbreviation of the time zone in effect at `ts`, such as
+    /// "UTC" or "CEST", if enabled.
+    fn with_time_zone(&self, formatted: String, ts: &MilliSecondsSinceUnixEpoch) -> String {
+        if !self.show_time_zone {
+            return formatted;
+        }
+        match Timestamp::from_millisecond(ts.0.into()) {
+            Ok(ts) => format!(
+                "{formatted} {}",
+                ts.to_zoned(self.time_zone.clone()).strftime("%Z")
+            ),
+            Err(_) => formatted,
+        }
+    }
+
+    /// Format just the time of day, for messages grouped under a header that
+    /// already shows the full timestamp.
+    pub fn format_time(&self, ts: &MilliSecondsSinceUnixEpoch) -> String {
+        let formatter =
+            TimeFormatter::try_new_with_length(&(&self.locale).into(), length::Time::Short)
+                .inspect_err(|err| log_formatter_error(&self.locale, err))
+                .ok();
+        let formatted = Timestamp::from_millisecond(ts.0.into()).map_or_else(
+            |_| "Unknown Time".to_string(),
+            |ts| {
+                format_time_with(
+                    formatter.as_ref(),
+                    ts.to_zoned(self.time_zone.clone()).time(),
+                )
+            },
+        );
+        self.with_time_zone(formatted, ts)
+    }
+
+    pub fn format_date(&self, ts: &MilliSecondsSinceUnixEpoch) -> String {
+        let formatter = TypedDateFormatter::<Gregorian>::try_new_with_length(
+            &(&self.locale).into(),
+            length::Date::Long,
+        )
+        .inspect_err(|err| log_formatter_error(&self.locale, err))
+        .ok();
+        self.day(ts).map_or_else(
+            || "Unknown Date".to_string(),
+            |date| format_date_with(formatter.as_ref(), date),
+        )
+    }
+}
+
+/// Pick the most preferred locale from an `Accept-Language` header.
+fn preferred_locale(accept_language: &str) -> Option<Locale> {
+    let mut languages: Vec<(f32, &str)> = accept_language
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = match parts.find_map(|param| param.trim().strip_prefix("q=")) {
+                Some(quality) => quality.parse().ok()?,
+                None => 1.0,
+            };
+            Some((quality, tag))
+        })
+        .collect();
+    // Stable, so languages with equal quality keep the order they were sent in.
+    languages.sort_by(|a, b| b.0.total_cmp(&a.0));
+    languages
+        .into_iter()
+        .filter(|(quality, tag)| *quality > 0.0 && *tag != "*")
+        .find_map(|(_, tag)| tag.parse().ok())
 }
+
 fn html_body(formatted_body: &FormattedBody) -> Option<&str> {
     if formatted_body.format == ruma::events::room::message::MessageFormat::Html {
         Some(&formatted_body.body)
@@ -27,6 +581,285 @@ fn html_body(formatted_body: &FormattedBody) -> Option<&str> {
         None
     }
 }
+/// Prepare a sanitized HTML body for a page, loading inline media through the
+/// proxy and hardening links.
+pub(crate) fn formatted_body_html(body: &str, media: &MediaRegistry) -> String {
+    let html = Html::parse(body);
+    for node in html.children() {
+        rewrite_node(&node, media);
+    }
+    html.to_string()
+}
+
+/// Rewrite an element of a sanitized HTML body and everything inside it for a
+/// page.
+fn rewrite_node(node: &NodeRef, media: &MediaRegistry) {
+    if let Some(element) = node.as_element() {
+        match &*element.name.local {
+            "img" => rewrite_inline_media(element, media),
+            "a" => harden_link(element),
+            _ => {}
+        }
+    }
+    for child in node.children() {
+        rewrite_node(&child, media);
+    }
+}
+
+/// Rewrite the `mxc://` source of an inline image, such as a custom emoji, to
+/// a media proxy URL so that it loads.
+fn rewrite_inline_media(element: &ElementData, media: &MediaRegistry) {
+    let mut attrs = element.attrs.borrow_mut();
+    *attrs = std::mem::take(&mut *attrs)
+        .into_iter()
+        .map(|mut attr| {
+            if &*attr.name.local == "src" && attr.value.starts_with("mxc://") {
+                if let Some(url) = media.mxc_url(<&MxcUri>::from(&*attr.value)) {
+                    attr.value = url.into();
+                }
+            }
+            attr
+        })
+        .collect();
+}
+
+/// Make an external link open in a new tab, without giving the linked page a
+/// handle on ours or the link any search ranking, as anyone can post links in
+/// a public archive.
+///
+/// Links to anchors and paths on this instance are left alone.
+fn harden_link(element: &ElementData) {
+    let mut attrs = element.attrs.borrow_mut();
+    let Some(href) = attrs
+        .iter()
+        .find(|attr| &*attr.name.local == "href")
+        .cloned()
+    else {
+        return;
+    };
+    if !is_external_link(&href.value) {
+        return;
+    }
+    attrs.retain(|attr| !matches!(&*attr.name.local, "rel" | "target"));
+    for (name, value) in [("rel", "nofollow noopener ugc"), ("target", "_blank")] {
+        let mut attr = href.clone();
+        attr.name.local = name.into();
+        attr.value = value.into();
+        attrs.insert(attr);
+    }
+}
+
+/// Whether a link's `href` points off this instance.
+fn is_external_link(href: &str) -> bool {
+    !(href.starts_with('#') || (href.starts_with('/') && !href.starts_with("//")))
+}
+
+/// A short description of who can join a room.
+pub(crate) fn join_rule_description(join_rule: &JoinRule) -> &'static str {
+    match join_rule {
+        JoinRule::Public => "Public room",
+        JoinRule::Invite | JoinRule::Private => "Invite-only room",
+        JoinRule::Knock => "Anyone can ask to join",
+        JoinRule::Restricted(_) => "Members of other rooms can join",
+        JoinRule::KnockRestricted(_) => {
+            "Members of other rooms can join, and anyone can ask to join"
+        }
+        _ => "Unknown join rule",
+    }
+}
+
+/// The rooms whose members are allowed to join a restricted room.
+pub(crate) fn join_rule_allowed_rooms(join_rule: &JoinRule) -> Vec<&RoomId> {
+    match join_rule {
+        JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) => restricted
+            .allow
+            .iter()
+            .filter_map(|rule| match rule {
+                AllowRule::RoomMembership(membership) => Some(&*membership.room_id),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether a room's name was computed from its members, rather than set
+/// explicitly or taken from an alias.
+pub(crate) fn is_computed_name(name: &RoomDisplayName) -> bool {
+    matches!(
+        name,
+        RoomDisplayName::Calculated(_) | RoomDisplayName::EmptyWas(_) | RoomDisplayName::Empty
+    )
+}
+
+/// Split a plain-text body into lines, so that line breaks can be kept when
+/// rendering it as HTML.
+pub(crate) fn plain_body_lines(body: &str) -> Vec<&str> {
+    body.lines().collect()
+}
+
+/// A short plain-text preview of a replied-to event.
+pub(crate) fn reply_preview(content: &TimelineItemContent) -> &str {
+    match content {
+        TimelineItemContent::MsgLike(msg_like) => match &msg_like.kind {
+            MsgLikeKind::Message(message) => message.msgtype.body(),
+            MsgLikeKind::Redacted => "Message was redacted.",
+            MsgLikeKind::UnableToDecrypt => "Unable to decrypt this message.",
+            MsgLikeKind::Hidden => "",
+        },
+        _ => "",
+    }
+}
+
+/// The media attached to an event, if it is a media message.
+pub(crate) fn content_attachment(content: &TimelineItemContent) -> Option<Attachment<'_>> {
+    match content {
+        TimelineItemContent::MsgLike(msg_like) => match &msg_like.kind {
+            MsgLikeKind::Message(message) => message_attachment(&message.msgtype),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A readable description of a state event, or `None` if it isn't a kind of
+/// state event we know how to describe.
+pub(crate) fn state_event_description(event: &TimelineEvent, state: &OtherState) -> Option<String> {
+    let sender = event.sender_name();
+    let description = match &state.content {
+        AnyFullStateEventContent::RoomMember(FullStateEventContent::Original {
+            content,
+            prev_content,
+        }) => {
+            let target = content.displayname.as_deref().unwrap_or(&state.state_key);
+            let is_self = event.sender.as_str() == state.state_key;
+            let prev_membership = prev_content.as_ref().map(|prev| &prev.membership);
+            let description = match (&content.membership, prev_membership) {
+                (MembershipState::Join, Some(MembershipState::Join)) => {
+                    format!("{target} changed their profile")
+                }
+                (MembershipState::Join, _) => format!("{target} joined the room"),
+                (MembershipState::Invite, _) => match &content.third_party_invite {
+                    Some(third_party_invite) => format!(
+                        "{sender} invited {target}, who accepted the invite sent to {}",
+                        third_party_invite.display_name
+                    ),
+                    None => format!("{sender} invited {target}"),
+                },
+                (MembershipState::Knock, _) => format!("{target} knocked to join the room"),
+                (MembershipState::Ban, _) => format!("{sender} banned {target}"),
+                (MembershipState::Leave, Some(MembershipState::Ban)) => {
+                    format!("{sender} unbanned {target}")
+                }
+                (MembershipState::Leave, Some(MembershipState::Invite)) if is_self => {
+                    format!("{target} rejected the invite")
+                }
+                (MembershipState::Leave, Some(MembershipState::Invite)) => {
+                    format!("{sender} withdrew the invite for {target}")
+                }
+                (MembershipState::Leave, Some(MembershipState::Knock)) if is_self => {
+                    format!("{target} withdrew their knock")
+                }
+                (MembershipState::Leave, Some(MembershipState::Knock)) => {
+                    format!("{sender} rejected the knock from {target}")
+                }
+                (MembershipState::Leave, _) if is_self => format!("{target} left the room"),
+                (MembershipState::Leave, _) => format!("{sender} removed {target}"),
+                _ => return None,
+            };
+            match &content.reason {
+                Some(reason) => format!("{description}: {reason}"),
+                None => description,
+            }
+        }
+        AnyFullStateEventContent::RoomThirdPartyInvite(FullStateEventContent::Original {
+            content,
+            ..
+        }) => format!(
+            "{sender} invited {} to join the room via a third-party invite",
+            content.display_name
+        ),
+        AnyFullStateEventContent::RoomThirdPartyInvite(FullStateEventContent::Redacted(_)) => {
+            format!("{sender} revoked a third-party invite")
+        }
+        AnyFullStateEventContent::RoomName(FullStateEventContent::Original { content, .. }) => {
+            format!("{sender} changed the room name to {}", content.name)
+        }
+        AnyFullStateEventContent::RoomTopic(FullStateEventContent::Original {
+            content, ..
+        }) => format!("{sender} changed the room topic to {}", content.topic),
+        AnyFullStateEventContent::RoomServerAcl(FullStateEventContent::Original {
+            content,
+            prev_content,
+        }) => server_acl_description(&sender, content, prev_content.as_ref()),
+        _ => return None,
+    };
+    Some(description)
+}
+
+/// Describe a change to a room's server ACL by what it did to each server,
+/// as moderators usually change one or two entries at a time.
+fn server_acl_description(
+    sender: &str,
+    content: &RoomServerAclEventContent,
+    prev_content: Option<&RoomServerAclEventContent>,
+) -> String {
+    let Some(prev_content) = prev_content else {
+        let mut description = format!(
+            "{sender} set the server ACL, allowing {}",
+            content.allow.join(", ")
+        );
+        if !content.deny.is_empty() {
+            description.push_str(&format!(" and banning {}", content.deny.join(", ")));
+        }
+        return description;
+    };
+
+    let added = |new: &[String], old: &[String]| -> Vec<String> {
+        new.iter()
+            .filter(|server| !old.contains(server))
+            .cloned()
+            .collect()
+    };
+    let mut changes = Vec::new();
+    let banned = added(&content.deny, &prev_content.deny);
+    if !banned.is_empty() {
+        changes.push(format!("banned {} from the room", banned.join(", ")));
+    }
+    let unbanned = added(&prev_content.deny, &content.deny);
+    if !unbanned.is_empty() {
+        changes.push(format!("unbanned {}", unbanned.join(", ")));
+    }
+    let allowed = added(&content.allow, &prev_content.allow);
+    if !allowed.is_empty() {
+        changes.push(format!("allowed {}", allowed.join(", ")));
+    }
+    let disallowed = added(&prev_content.allow, &content.allow);
+    if !disallowed.is_empty() {
+        changes.push(format!("stopped allowing {}", disallowed.join(", ")));
+    }
+    if content.allow_ip_literals != prev_content.allow_ip_literals {
+        changes.push(if content.allow_ip_literals {
+            "allowed servers named by IP address".to_owned()
+        } else {
+            "banned servers named by IP address".to_owned()
+        });
+    }
+
+    if changes.is_empty() {
+        format!("{sender} updated the server ACL without changing it")
+    } else {
+        format!("{sender} {}", changes.join("; "))
+    }
+}
+
+/// Pretty-print an event's JSON, for events we can't otherwise display.
+pub(crate) fn pretty_json(raw: &RawValue) -> String {
+    serde_json::from_str::<serde_json::Value>(raw.get())
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or_else(|_| raw.get().to_owned())
+}
+
 pub(crate) fn message_formatted_body(message: &MessageType) -> Option<&FormattedBody> {
     match message {
         MessageType::Audio(audio_message_event_content) => {
@@ -57,11 +890,243 @@ pub(crate) fn message_formatted_body(message: &MessageType) -> Option<&Formatted
     }
 }
 
+/// A point on the map a location message refers to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct GeoLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GeoLocation {
+    /// Parse a `geo:` URI (RFC 5870), ignoring any altitude and parameters.
+    fn parse(uri: &str) -> Option<Self> {
+        let coordinates = uri.strip_prefix("geo:")?;
+        let coordinates = coordinates.split([';', '?']).next()?;
+        let mut parts = coordinates.split(',');
+        let latitude: f64 = parts.next()?.parse().ok()?;
+        let longitude: f64 = parts.next()?.parse().ok()?;
+        if let Some(altitude) = parts.next() {
+            altitude.parse::<f64>().ok()?;
+        }
+        let valid = parts.next().is_none()
+            && (-90.0..=90.0).contains(&latitude)
+            && (-180.0..=180.0).contains(&longitude);
+        valid.then_some(Self {
+            latitude,
+            longitude,
+        })
+    }
+
+    pub fn map_url(&self) -> String {
+        format!(
+            "https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map=16/{lat}/{lon}",
+            lat = self.latitude,
+            lon = self.longitude,
+        )
+    }
+}
+
+impl std::fmt::Display for GeoLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}", self.latitude, self.longitude)
+    }
+}
+
+/// The location a location message refers to.
+///
+/// Some legacy clients only put the location in the body, so if `geo_uri`
+/// isn't valid this falls back to a `geo:` URI that makes up a whole word of
+/// the body.
+pub(crate) fn message_location(message: &MessageType) -> Option<GeoLocation> {
+    let MessageType::Location(content) = message else {
+        return None;
+    };
+    GeoLocation::parse(&content.geo_uri)
+        .or_else(|| content.body.split_whitespace().find_map(GeoLocation::parse))
+}
+
+/// The kind of media attached to a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AttachmentKind {
+    Image,
+    Video,
+    Audio,
+    File,
+}
+
+/// The media attached to a message, and its caption.
+pub(crate) struct Attachment<'a> {
+    pub kind: AttachmentKind,
+    pub source: &'a MediaSource,
+    pub mimetype: Option<&'a str>,
+    pub filename: &'a str,
+    /// The plain-text caption, if the body isn't just the file name.
+    pub caption: Option<&'a str>,
+    pub formatted_caption: Option<&'a FormattedBody>,
+    /// A smaller version of the media, if the sender provided one.
+    pub thumbnail_source: Option<&'a MediaSource>,
+    /// The intrinsic width and height of the media, if the sender gave them.
+    pub dimensions: Option<(u64, u64)>,
+}
+
+/// The largest size an image or video is shown at inline.
+pub(crate) const ATTACHMENT_MAX_WIDTH: u64 = 800;
+pub(crate) const ATTACHMENT_MAX_HEIGHT: u64 = 600;
+
+/// How much longer one side of an image can be than the other before it's
+/// shown collapsed, since it would be unreadable when scaled to fit.
+const EXTREME_ASPECT_RATIO: u64 = 4;
+
+impl Attachment<'_> {
+    /// Whether the media was end-to-end encrypted, which is the case when
+    /// the message has encrypted file info rather than a plain URL.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.source, MediaSource::Encrypted(_))
+    }
+
+    /// A short label for the kind of media, for text previews.
+    pub fn label(&self) -> &'static str {
+        match self.kind {
+            AttachmentKind::Image => "Image",
+            AttachmentKind::Video => "Video",
+            AttachmentKind::Audio => "Audio",
+            AttachmentKind::File => "File",
+        }
+    }
+
+    /// A proxy URL for a small preview image of the media, if there is one.
+    pub fn preview_url(&self, media: &MediaRegistry, size: u32) -> Option<String> {
+        match (self.kind, self.thumbnail_source) {
+            (AttachmentKind::Image, _) => {
+                media.thumbnail_url(self.source, self.mimetype, size, size)
+            }
+            (_, Some(thumbnail)) => media.url(thumbnail, None),
+            _ => None,
+        }
+    }
+
+    /// The size to show the media at, scaled down to fit inline while keeping
+    /// its aspect ratio, so the space can be reserved before it loads.
+    pub fn display_size(&self) -> Option<(u64, u64)> {
+        let (width, height) = self.dimensions.filter(|(w, h)| *w > 0 && *h > 0)?;
+        if width <= ATTACHMENT_MAX_WIDTH && height <= ATTACHMENT_MAX_HEIGHT {
+            return Some((width, height));
+        }
+        // Compare the scale factors without dividing, to stay in integers
+        if width * ATTACHMENT_MAX_HEIGHT >= height * ATTACHMENT_MAX_WIDTH {
+            let scaled = (height * ATTACHMENT_MAX_WIDTH / width).max(1);
+            Some((ATTACHMENT_MAX_WIDTH, scaled))
+        } else {
+            let scaled = (width * ATTACHMENT_MAX_HEIGHT / height).max(1);
+            Some((scaled, ATTACHMENT_MAX_HEIGHT))
+        }
+    }
+
+    /// Whether the media is too large to show inline and has such an extreme
+    /// aspect ratio that it needs expanding to be readable.
+    pub fn is_oversized(&self) -> bool {
+        let Some((width, height)) = self.dimensions else {
+            return false;
+        };
+        let exceeds_bounds = width > ATTACHMENT_MAX_WIDTH || height > ATTACHMENT_MAX_HEIGHT;
+        let extreme = width > height.saturating_mul(EXTREME_ASPECT_RATIO)
+            || height > width.saturating_mul(EXTREME_ASPECT_RATIO);
+        exceeds_bounds && extreme
+    }
+}
+
+/// The media attached to a message, or `None` if it isn't a media message.
+pub(crate) fn message_attachment(message: &MessageType) -> Option<Attachment<'_>> {
+    let attachment = match message {
+        MessageType::Image(content) => Attachment {
+            kind: AttachmentKind::Image,
+            source: &content.source,
+            mimetype: content
+                .info
+                .as_ref()
+                .and_then(|info| info.mimetype.as_deref()),
+            filename: content.filename(),
+            caption: content.caption(),
+            formatted_caption: content.formatted_caption(),
+            thumbnail_source: content
+                .info
+                .as_ref()
+                .and_then(|info| info.thumbnail_source.as_ref()),
+            dimensions: content
+                .info
+                .as_ref()
+                .and_then(|info| Some((info.width?.into(), info.height?.into()))),
+        },
+        MessageType::Video(content) => Attachment {
+            kind: AttachmentKind::Video,
+            source: &content.source,
+            mimetype: content
+                .info
+                .as_ref()
+                .and_then(|info| info.mimetype.as_deref()),
+            filename: content.filename(),
+            caption: content.caption(),
+            formatted_caption: content.formatted_caption(),
+            thumbnail_source: content
+                .info
+                .as_ref()
+                .and_then(|info| info.thumbnail_source.as_ref()),
+            dimensions: content
+                .info
+                .as_ref()
+                .and_then(|info| Some((info.width?.into(), info.height?.into()))),
+        },
+        MessageType::Audio(content) => Attachment {
+            kind: AttachmentKind::Audio,
+            source: &content.source,
+            mimetype: content
+                .info
+                .as_ref()
+                .and_then(|info| info.mimetype.as_deref()),
+            filename: content.filename(),
+            caption: content.caption(),
+            formatted_caption: content.formatted_caption(),
+            thumbnail_source: None,
+            dimensions: None,
+        },
+        MessageType::File(content) => Attachment {
+            kind: AttachmentKind::File,
+            source: &content.source,
+            mimetype: content
+                .info
+                .as_ref()
+                .and_then(|info| info.mimetype.as_deref()),
+            filename: content.filename(),
+            caption: content.caption(),
+            formatted_caption: content.formatted_caption(),
+            thumbnail_source: content
+                .info
+                .as_ref()
+                .and_then(|info| info.thumbnail_source.as_ref()),
+            dimensions: None,
+        },
+        _ => return None,
+    };
+    Some(attachment)
+}
+
 pub(crate) fn timestamp_to_string(ts: &MilliSecondsSinceUnixEpoch) -> String {
     milliseconds_since_unix_epoch_to_string(ts.0.into())
 }
-pub(crate) fn timestamp_to_format_string(ts: &MilliSecondsSinceUnixEpoch) -> String {
-    milliseconds_since_unix_epoch_to_format_string(ts.0.into())
+
+/// How long ago a timestamp was, in its largest whole unit, such as
+/// "2 minutes ago", or `None` if it was less than a minute ago.
+pub(crate) fn time_ago(ts: &MilliSecondsSinceUnixEpoch) -> Option<String> {
+    let now = u64::from(MilliSecondsSinceUnixEpoch::now().get());
+    let seconds = now.saturating_sub(ts.get().into()) / 1000;
+    let (count, unit) = match seconds {
+        0..60 => return None,
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    Some(format!("{count} {unit}{plural} ago"))
 }
 
 pub(crate) fn milliseconds_since_unix_epoch_to_string(milliseconds: i64) -> String {
@@ -69,15 +1134,33 @@ pub(crate) fn milliseconds_since_unix_epoch_to_string(milliseconds: i64) -> Stri
         .map_or_else(|_| "Unknown Time".to_string(), |ts| ts.to_string())
 }
 
-pub(crate) fn milliseconds_since_unix_epoch_to_format_string(milliseconds: i64) -> String {
-    let formatter =
-        TypedDateTimeFormatter::try_new(&locale!("en-GB").into(), Default::default()).unwrap();
+pub(crate) fn milliseconds_since_unix_epoch_to_format_string(
+    milliseconds: i64,
+    locale: &Locale,
+    time_zone: &TimeZone,
+) -> String {
+    let formatter = TypedDateTimeFormatter::try_new(&locale.into(), Default::default())
+        .inspect_err(|err| log_formatter_error(locale, err))
+        .ok();
+    format_datetime_with(formatter.as_ref(), milliseconds, time_zone)
+}
+
+/// Format a timestamp with `formatter`, or as ISO 8601 in UTC if no formatter
+/// could be built for the locale.
+fn format_datetime_with(
+    formatter: Option<&TypedDateTimeFormatter<Gregorian>>,
+    milliseconds: i64,
+    time_zone: &TimeZone,
+) -> String {
+    let Some(formatter) = formatter else {
+        return milliseconds_since_unix_epoch_to_string(milliseconds);
+    };
     Timestamp::from_millisecond(milliseconds).map_or_else(
         |_| "Unknown Time".to_string(),
         |ts| {
             formatter
                 .format(
-                    &convert_from_datetime(ts.in_tz("UTC").unwrap().datetime())
+                    &convert_from_datetime(ts.to_zoned(time_zone.clone()).datetime())
                         .to_calendar(Gregorian),
                 )
                 .to_string()
@@ -85,6 +1168,43 @@ pub(crate) fn milliseconds_since_unix_epoch_to_format_string(milliseconds: i64)
     )
 }
 
+/// Format a date with `formatter`, or as an ISO 8601 date if no formatter
+/// could be built for the locale.
+fn format_date_with(
+    formatter: Option<&TypedDateFormatter<Gregorian>>,
+    date: jiff::civil::Date,
+) -> String {
+    match formatter {
+        Some(formatter) => formatter
+            .format(&convert_from_date(date).to_calendar(Gregorian))
+            .to_string(),
+        None => date.to_string(),
+    }
+}
+
+/// Format a time of day with `formatter`, or as 24-hour `HH:MM` if no
+/// formatter could be built for the locale.
+fn format_time_with(formatter: Option<&TimeFormatter>, time: jiff::civil::Time) -> String {
+    match formatter {
+        Some(formatter) => formatter.format(&convert_from_time(time)).to_string(),
+        None => time.strftime("%H:%M").to_string(),
+    }
+}
+
+/// Whether a formatter has failed to build yet, so the failure is only logged
+/// once rather than for every timestamp on every page.
+static FORMATTER_ERROR_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Log that a date or time formatter couldn't be built for `locale`, so ISO
+/// 8601 is shown instead.
+fn log_formatter_error(locale: &Locale, err: impl Display) {
+    if !FORMATTER_ERROR_LOGGED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "Failed to build a date formatter for locale {locale}, showing ISO 8601 timestamps instead: {err}"
+        );
+    }
+}
+
 use icu::calendar::{Date as IcuDate, DateTime as IcuDateTime, Iso, Time as IcuTime};
 
 fn convert_from_datetime(v: jiff::civil::DateTime) -> IcuDateTime<Iso> {
@@ -108,3 +1228,67 @@ fn convert_from_time(v: jiff::civil::Time) -> IcuTime {
     // All Jiff civil times are valid ICU4X times.
     IcuTime::try_new(hour, minute, second, subsec).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_body_lines_splits_on_line_breaks() {
+        assert_eq!(plain_body_lines("one\ntwo\nthree"), ["one", "two", "three"]);
+        assert_eq!(plain_body_lines("one\r\ntwo\r\n"), ["one", "two"]);
+        assert_eq!(plain_body_lines("one\n\ntwo\n"), ["one", "", "two"]);
+        assert_eq!(plain_body_lines("one"), ["one"]);
+        assert!(plain_body_lines("").is_empty());
+    }
+
+    #[test]
+    fn timestamps_fall_back_to_iso_8601_without_a_formatter() {
+        // 2024-01-15T12:00:00Z
+        let milliseconds = 1_705_320_000_000;
+        let time_zone = TimeZone::UTC;
+        assert_eq!(
+            format_datetime_with(None, milliseconds, &time_zone),
+            "2024-01-15T12:00:00Z"
+        );
+        let datetime = Timestamp::from_millisecond(milliseconds)
+            .unwrap()
+            .to_zoned(time_zone);
+        assert_eq!(format_date_with(None, datetime.date()), "2024-01-15");
+        assert_eq!(format_time_with(None, datetime.time()), "12:00");
+    }
+
+    #[test]
+    fn external_links_are_hardened() {
+        let media = MediaRegistry::new(&[0; 32], 1);
+        let html = formatted_body_html(
+            r##"<a href="https://example.com" target="_self">out</a> <a href="/room/x">in</a> <a href="#event-1">here</a>"##,
+            &media,
+        );
+        assert_eq!(html.matches(r#"rel="nofollow noopener ugc""#).count(), 1);
+        assert_eq!(html.matches(r#"target="_blank""#).count(), 1);
+        assert!(!html.contains("_self"));
+    }
+
+    #[test]
+    fn markup_inside_attributes_is_left_alone() {
+        let media = MediaRegistry::new(&[0; 32], 1);
+        let html = formatted_body_html(
+            r#"<span title="<a href=&quot;https://example.com&quot;>">text</span>"#,
+            &media,
+        );
+        assert!(!html.contains("nofollow"));
+    }
+
+    #[test]
+    fn inline_mxc_images_are_proxied() {
+        let media = MediaRegistry::new(&[0; 32], 1);
+        let html = formatted_body_html(
+            r#"<img src="mxc://example.com/emoji" alt=":x:"> <img src="https://example.com/x.png">"#,
+            &media,
+        );
+        assert!(!html.contains("mxc://"));
+        assert!(html.contains("/media/example.com/emoji?sig="));
+        assert!(html.contains("https://example.com/x.png"));
+    }
+}
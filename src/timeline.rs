@@ -1,20 +1,58 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use color_eyre::eyre;
+use futures::{FutureExt, future::BoxFuture};
+use icu::normalizer::ComposingNormalizer;
+use matrix_sdk::deserialized_responses::VerificationState;
 use ruma::{
-    MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri, OwnedUserId, RoomId,
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri, OwnedServerName, OwnedUserId,
+    RoomId, UserId,
     events::{
-        AnyFullStateEventContent, AnySyncMessageLikeEvent, AnySyncTimelineEvent, StateEventType,
+        AnyFullStateEventContent, AnySyncMessageLikeEvent, AnySyncTimelineEvent, Mentions,
+        StateEventType, SyncMessageLikeEvent,
         room::message::{MessageType, Relation, RoomMessageEventContentWithoutRelation},
     },
     html::RemoveReplyFallback,
 };
 use serde_json::value::RawValue;
+use tracing::{debug, warn};
 
+/// Options controlling how timeline events are built.
+#[derive(Clone, Debug)]
+pub struct TimelineOptions {
+    /// How many levels of replied-to events to fetch.
+    pub reply_depth: usize,
+    /// How long to wait for the homeserver when fetching an event.
+    pub fetch_timeout: Duration,
+    /// Whether to recognise the original authors of messages relayed by
+    /// bridges.
+    pub bridge_attribution: bool,
+    /// Relay bots whose messages are prefixed with their original author's
+    /// name.
+    pub bridge_bots: Vec<OwnedUserId>,
+    /// Whether to record the homeserver each event came from.
+    pub show_origin_server: bool,
+    /// Users whose intentional mentions are highlighted, besides our own.
+    pub highlight_users: Vec<OwnedUserId>,
+    /// Whether media in events that weren't sent from a verified device is
+    /// only loaded on request.
+    pub gate_unverified_media: bool,
+    /// Whether only the local store is used, without contacting the
+    /// homeserver.
+    pub offline: bool,
+}
+
+/// Build a timeline event, fetching the events it replies to up to
+/// `options.reply_depth` levels deep.
 pub async fn build_timeline_event(
     client: &matrix_sdk::Client,
     room_id: &RoomId,
     event: matrix_sdk::deserialized_responses::TimelineEvent,
+    options: &TimelineOptions,
 ) -> eyre::Result<TimelineEvent> {
     let event_de = event.raw().deserialize()?;
     let sender = event_de.sender();
@@ -24,8 +62,8 @@ pub async fn build_timeline_event(
     let sender_profile = if let Some(ref room) = room {
         let mut profile = room.get_member_no_sync(sender).await?;
 
-        // Fallback to the slow path.
-        if profile.is_none() {
+        // Fallback to the slow path, which fetches the member list.
+        if profile.is_none() && !options.offline {
             profile = room.get_member(sender).await?;
         }
         profile.as_mut().map(|profile| Profile {
@@ -39,8 +77,55 @@ pub async fn build_timeline_event(
     let is_room_encrypted = room
         .map(|r| r.encryption_state().is_encrypted())
         .unwrap_or(false);
+    let is_sender_verified = event
+        .encryption_info()
+        .is_some_and(|info| matches!(info.verification_state, VerificationState::Verified));
 
-    let content = build_timeline_item(&event_de).await?;
+    let mut content = build_timeline_item(&event_de).await?;
+    let is_highlighted = match &content {
+        TimelineItemContent::MsgLike(MsgLikeContent {
+            kind: MsgLikeKind::Message(message),
+            ..
+        }) => {
+            message.mentions_room()
+                || client
+                    .user_id()
+                    .into_iter()
+                    .chain(options.highlight_users.iter().map(|user| &**user))
+                    .any(|user| message.mentions_user(user))
+        }
+        _ => false,
+    };
+    let bridged_sender = if options.bridge_attribution {
+        let is_relay = options.bridge_bots.iter().any(|bot| &**bot == sender);
+        bridged_sender(event.raw().json(), &content, is_relay)
+    } else {
+        None
+    };
+
+    if let TimelineItemContent::MsgLike(MsgLikeContent {
+        in_reply_to: Some(in_reply_to),
+        ..
+    }) = &mut content
+    {
+        if options.reply_depth > 0 {
+            let options = TimelineOptions {
+                reply_depth: options.reply_depth - 1,
+                ..options.clone()
+            };
+            in_reply_to.event =
+                build_replied_to_event(client, room_id, &in_reply_to.event_id, options)
+                    .await
+                    .inspect_err(|e| {
+                        warn!(
+                            "Failed to fetch replied-to event {}: {e}",
+                            in_reply_to.event_id
+                        );
+                    })
+                    .ok()
+                    .map(Box::new);
+        }
+    }
 
     Ok(TimelineEvent {
         sender: sender.into(),
@@ -48,11 +133,197 @@ pub async fn build_timeline_event(
         timestamp,
         content,
         is_room_encrypted,
+        bridged_sender,
+        origin_server: options
+            .show_origin_server
+            .then(|| sender.server_name().to_owned()),
+        is_highlighted,
+        gate_media: options.gate_unverified_media && is_room_encrypted && !is_sender_verified,
+        sent_before_join: false,
         event_id: event.event_id(),
         raw: event.into_raw().into_json(),
     })
 }
 
+/// Fetch and build the event being replied to.
+///
+/// This is boxed as it recurses through [`build_timeline_event`].
+fn build_replied_to_event<'a>(
+    client: &'a matrix_sdk::Client,
+    room_id: &'a RoomId,
+    event_id: &'a EventId,
+    options: TimelineOptions,
+) -> BoxFuture<'a, eyre::Result<RepliedToEvent>> {
+    async move {
+        let room = client
+            .get_room(room_id)
+            .ok_or_else(|| eyre::eyre!("Unknown room {room_id}"))?;
+        let event = if options.offline {
+            let (room_cache, _drop_handles) = room.event_cache().await?;
+            room_cache
+                .event(event_id)
+                .await
+                .ok_or_else(|| eyre::eyre!("Event {event_id} isn't stored locally"))?
+        } else {
+            tokio::time::timeout(options.fetch_timeout, room.event(event_id, None))
+                .await
+                .map_err(|_| eyre::eyre!("Timed out fetching event {event_id}"))??
+        };
+        let event = build_timeline_event(client, room_id, event, &options).await?;
+        Ok(RepliedToEvent {
+            content: event.content,
+            sender: event.sender,
+            sender_profile: event.sender_profile,
+        })
+    }
+    .boxed()
+}
+
+/// The original author of a message relayed by a bridge, if it can be
+/// recognised.
+///
+/// This understands per-message profiles (MSC4144) from any sender. The
+/// `<name> message` and `[name] message` prefixes used by IRC-style relays are
+/// only read when `is_relay`, since anyone else may start a message that way.
+fn bridged_sender(raw: &RawValue, content: &TimelineItemContent, is_relay: bool) -> Option<String> {
+    #[derive(serde::Deserialize)]
+    struct RawEvent {
+        content: RawContent,
+    }
+    #[derive(serde::Deserialize)]
+    struct RawContent {
+        #[serde(rename = "com.beeper.per_message_profile")]
+        per_message_profile: Option<PerMessageProfile>,
+    }
+    #[derive(serde::Deserialize)]
+    struct PerMessageProfile {
+        displayname: Option<String>,
+    }
+
+    let profile_name = serde_json::from_str::<RawEvent>(raw.get())
+        .ok()
+        .and_then(|event| event.content.per_message_profile)
+        .and_then(|profile| profile.displayname);
+    if profile_name.is_some() {
+        return profile_name;
+    }
+    if !is_relay {
+        return None;
+    }
+
+    let TimelineItemContent::MsgLike(MsgLikeContent {
+        kind: MsgLikeKind::Message(message),
+        ..
+    }) = content
+    else {
+        return None;
+    };
+    if !matches!(
+        message.msgtype,
+        MessageType::Text(_) | MessageType::Notice(_) | MessageType::Emote(_)
+    ) {
+        return None;
+    }
+    let body = message.msgtype.body();
+    [("<", "> "), ("[", "] ")]
+        .into_iter()
+        .find_map(|(open, close)| {
+            let (name, _) = body.strip_prefix(open)?.split_once(close)?;
+            let plausible = !name.trim().is_empty()
+                && name.chars().count() <= 32
+                && !name.contains(['\n', '<', '>', '[', ']']);
+            plausible.then(|| name.to_owned())
+        })
+}
+
+/// Remove repeated events from a chunk, keeping the first occurrence.
+///
+/// Federation and re-delivery can put the same event in a chunk twice, which
+/// would otherwise render it twice with clashing anchors.
+pub fn dedup_events(events: &mut Vec<TimelineEvent>) {
+    let mut seen = HashSet::new();
+    events.retain(|event| match &event.event_id {
+        Some(event_id) => seen.insert(event_id.clone()),
+        None => true,
+    });
+}
+
+/// Attach the reactions in a chunk of events to the events they react to.
+///
+/// Reactions are only counted if they are in the same chunk as their target.
+/// A sender who reacted with the same key more than once is counted from
+/// their earliest reaction, so the result doesn't depend on the order of the
+/// chunk.
+pub fn aggregate_reactions(events: &mut [TimelineEvent]) {
+    let mut reactions: HashMap<OwnedEventId, ReactionsByKeyBySender> = HashMap::new();
+    for event in events.iter() {
+        let reaction = match serde_json::from_str(event.raw.get()) {
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Original(reaction),
+            ))) => reaction,
+            // Redacted reactions have lost their key and target, so there is
+            // nothing to count them towards.
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Redacted(_),
+            ))) => continue,
+            _ => continue,
+        };
+        let annotation = reaction.content.relates_to;
+        let key = normalize_reaction_key(&annotation.key);
+        if key.trim().is_empty() {
+            continue;
+        }
+        let info = ReactionInfo {
+            timestamp: reaction.origin_server_ts,
+            sender_profile: event.sender_profile.clone(),
+        };
+        reactions
+            .entry(annotation.event_id)
+            .or_default()
+            .0
+            .entry(key)
+            .or_default()
+            .entry(reaction.sender)
+            .and_modify(|existing| {
+                if info.timestamp < existing.timestamp {
+                    *existing = info.clone();
+                }
+            })
+            .or_insert(info);
+    }
+
+    for event in events {
+        if let (Some(event_id), TimelineItemContent::MsgLike(msg_like)) =
+            (&event.event_id, &mut event.content)
+        {
+            if let Some(event_reactions) = reactions.remove(event_id) {
+                msg_like.reactions = event_reactions;
+            }
+        }
+    }
+}
+
+/// Normalise a reaction key, so that the same emoji sent by different clients
+/// is counted together.
+///
+/// Keys are NFC normalised and stripped of variation selectors. A lone
+/// non-ASCII symbol is then given the emoji presentation selector, so that
+/// emoji which default to text presentation (like ❤️) still display as emoji.
+pub fn normalize_reaction_key(key: &str) -> String {
+    let mut key: String = ComposingNormalizer::new_nfc()
+        .normalize(key)
+        .chars()
+        .filter(|c| !matches!(c, '\u{FE0E}' | '\u{FE0F}'))
+        .collect();
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if !c.is_ascii() && !c.is_alphanumeric() {
+            key.push('\u{FE0F}');
+        }
+    }
+    key
+}
+
 pub async fn build_timeline_item(
     event: &AnySyncTimelineEvent,
 ) -> eyre::Result<TimelineItemContent> {
@@ -77,6 +348,7 @@ async fn messagelike_to_content(
                 let msgtype = original_sync_message_like_event.content.msgtype.clone();
                 let message = Message::from_event(
                     msgtype,
+                    original_sync_message_like_event.content.mentions.clone(),
                     original_sync_message_like_event
                         .unsigned
                         .relations
@@ -88,11 +360,42 @@ async fn messagelike_to_content(
                         }),
                 );
 
+                let (in_reply_to, thread_root) =
+                    match &original_sync_message_like_event.content.relates_to {
+                        Some(Relation::Reply { in_reply_to }) => (
+                            Some(InReplyToDetails {
+                                event_id: in_reply_to.event_id.clone(),
+                                event: None,
+                                replies_to_previous: false,
+                            }),
+                            None,
+                        ),
+                        Some(Relation::Thread(thread)) => (
+                            thread
+                                .in_reply_to
+                                .as_ref()
+                                .filter(|_| !thread.is_falling_back)
+                                .map(|in_reply_to| InReplyToDetails {
+                                    event_id: in_reply_to.event_id.clone(),
+                                    event: None,
+                                    replies_to_previous: false,
+                                }),
+                            Some(thread.event_id.clone()),
+                        ),
+                        _ => (None, None),
+                    };
+
                 TimelineItemContent::MsgLike(MsgLikeContent {
                     kind: MsgLikeKind::Message(message),
                     reactions: ReactionsByKeyBySender::default(),
-                    in_reply_to: None,
-                    thread_root: None,
+                    in_reply_to,
+                    thread_root,
+                    thread_reply_count: original_sync_message_like_event
+                        .unsigned
+                        .relations
+                        .thread
+                        .as_ref()
+                        .map(|thread| thread.count.into()),
                 })
             }
             ruma::events::SyncMessageLikeEvent::Redacted(_) => {
@@ -101,9 +404,19 @@ async fn messagelike_to_content(
                     reactions: ReactionsByKeyBySender::default(),
                     in_reply_to: None,
                     thread_root: None,
+                    thread_reply_count: None,
                 })
             }
         },
+        // Events that were decrypted are passed on as the event they
+        // contained, so any still encrypted couldn't be decrypted.
+        AnySyncMessageLikeEvent::RoomEncrypted(_) => TimelineItemContent::MsgLike(MsgLikeContent {
+            kind: MsgLikeKind::UnableToDecrypt,
+            reactions: ReactionsByKeyBySender::default(),
+            in_reply_to: None,
+            thread_root: None,
+            thread_reply_count: None,
+        }),
         AnySyncMessageLikeEvent::Reaction(_) | AnySyncMessageLikeEvent::RoomRedaction(_) => {
             let reactions = ReactionsByKeyBySender::default();
             TimelineItemContent::MsgLike(MsgLikeContent {
@@ -111,11 +424,25 @@ async fn messagelike_to_content(
                 reactions,
                 in_reply_to: None,
                 thread_root: None,
+                thread_reply_count: None,
+            })
+        }
+        // Stickers, polls, calls, custom types, and ephemeral events a server
+        // put in the timeline aren't rendered, but shouldn't stop the rest of
+        // the page from rendering either.
+        _ => {
+            debug!(
+                "Hiding unsupported {} event in the timeline",
+                msg_like.event_type()
+            );
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Hidden,
+                reactions: ReactionsByKeyBySender::default(),
+                in_reply_to: None,
+                thread_root: None,
+                thread_reply_count: None,
             })
         }
-        _ => Err(eyre::eyre!(
-            "Unsupported message-like event type {msg_like:?}"
-        ))?,
     };
     Ok(content)
 }
@@ -136,11 +463,100 @@ pub struct TimelineEvent {
     ///
     /// May be false when we don't know about the room encryption status yet.
     pub is_room_encrypted: bool,
+    /// The original author of the message, if it was relayed by a bridge.
+    pub bridged_sender: Option<String>,
+    /// The homeserver the event was sent from, if it is to be shown.
+    pub origin_server: Option<OwnedServerName>,
+    /// Whether the event intentionally mentions us, a configured user, or
+    /// the whole room.
+    pub is_highlighted: bool,
+    /// Whether attachments are only loaded on request, because the event is
+    /// in an encrypted room but wasn't sent from a verified device.
+    pub gate_media: bool,
+    /// Whether the event was sent before our account joined the room, so it
+    /// was backfilled rather than received live.
+    pub sent_before_join: bool,
 
     /// The JSON serialization of the event.
     pub raw: Box<RawValue>,
 }
 
+impl TimelineEvent {
+    /// Whether this event is only shown through the events it relates to,
+    /// like a reaction.
+    pub fn is_hidden(&self) -> bool {
+        matches!(
+            &self.content,
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Hidden,
+                ..
+            })
+        )
+    }
+
+    /// Whether this is a text message with no visible text.
+    pub fn is_empty_message(&self) -> bool {
+        matches!(
+            &self.content,
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Message(message),
+                ..
+            }) if message.is_empty()
+        )
+    }
+
+    /// Whether this is a local echo of an event we are sending, which the
+    /// homeserver hasn't given an ID yet.
+    pub fn is_local_echo(&self) -> bool {
+        self.event_id.is_none()
+    }
+
+    /// Whether this is a message intentionally mentioning `user`.
+    pub fn mentions(&self, user: &UserId) -> bool {
+        match &self.content {
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Message(message),
+                ..
+            }) => message.mentions_user(user),
+            _ => false,
+        }
+    }
+
+    /// The message type of a message, without the `m.` prefix, such as
+    /// `text` or `image`.
+    pub fn message_type(&self) -> Option<&str> {
+        match &self.content {
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Message(message),
+                ..
+            }) => {
+                let msgtype = message.msgtype.msgtype();
+                Some(msgtype.strip_prefix("m.").unwrap_or(msgtype))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this is an `m.notice` message, as sent by bots.
+    pub fn is_notice(&self) -> bool {
+        matches!(
+            &self.content,
+            TimelineItemContent::MsgLike(MsgLikeContent {
+                kind: MsgLikeKind::Message(message),
+                ..
+            }) if matches!(message.msgtype, MessageType::Notice(_))
+        )
+    }
+
+    /// The sender's display name, or their user ID if they don't have one.
+    pub fn sender_name(&self) -> &str {
+        self.sender_profile
+            .as_ref()
+            .and_then(|profile| profile.display_name.as_deref())
+            .unwrap_or(self.sender.as_str())
+    }
+}
+
 /// The display name and avatar URL of a room member.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Profile {
@@ -203,6 +619,8 @@ pub struct MsgLikeContent {
     pub in_reply_to: Option<InReplyToDetails>,
     /// Event ID of the thread root, if this is a message in a thread.
     pub thread_root: Option<OwnedEventId>,
+    /// The number of messages in the thread this is the root of, if any.
+    pub thread_reply_count: Option<u64>,
 }
 /// Details about an event being replied to.
 #[derive(Clone, Debug)]
@@ -213,13 +631,27 @@ pub struct InReplyToDetails {
     /// The details of the event.
     /// Fetch if not there
     pub event: Option<Box<RepliedToEvent>>,
+
+    /// Whether the event replied to is the sender's own message just before
+    /// this one in the same group, so quoting it would be redundant.
+    pub replies_to_previous: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct RepliedToEvent {
-    content: TimelineItemContent,
-    sender: OwnedUserId,
-    sender_profile: Option<Profile>,
+    pub content: TimelineItemContent,
+    pub sender: OwnedUserId,
+    pub sender_profile: Option<Profile>,
+}
+
+impl RepliedToEvent {
+    /// The sender's display name, or their user ID if they don't have one.
+    pub fn sender_name(&self) -> &str {
+        self.sender_profile
+            .as_ref()
+            .and_then(|profile| profile.display_name.as_deref())
+            .unwrap_or(self.sender.as_str())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -236,13 +668,18 @@ pub enum MsgLikeKind {
 pub struct Message {
     pub msgtype: MessageType,
     pub edited: bool,
+    /// The intentional mentions (`m.mentions`) of the message, if it has any.
+    pub mentions: Option<Mentions>,
 }
 
 impl Message {
     pub fn from_event(
         mut msgtype: MessageType,
+        mentions: Option<Mentions>,
         edit: Option<RoomMessageEventContentWithoutRelation>,
     ) -> Self {
+        // Compat mode keeps every element the spec allows in formatted bodies,
+        // including tables, so only unsafe markup is removed.
         msgtype.sanitize(
             ruma::html::HtmlSanitizerMode::Compat,
             RemoveReplyFallback::Yes,
@@ -250,14 +687,42 @@ impl Message {
         let mut msg = Self {
             msgtype,
             edited: false,
+            mentions,
         };
         if let Some(edit) = edit {
             msg.apply_edit(edit);
         }
         msg
     }
+    /// Whether the message intentionally mentions `user`.
+    pub fn mentions_user(&self, user: &UserId) -> bool {
+        self.mentions
+            .as_ref()
+            .is_some_and(|mentions| mentions.user_ids.contains(user))
+    }
+
+    /// Whether the message intentionally mentions the whole room.
+    pub fn mentions_room(&self) -> bool {
+        self.mentions.as_ref().is_some_and(|mentions| mentions.room)
+    }
+
+    /// Whether this is a text message with no visible text, such as one with
+    /// an empty or whitespace-only body.
+    pub fn is_empty(&self) -> bool {
+        let formatted = match &self.msgtype {
+            MessageType::Text(content) => content.formatted.as_ref(),
+            MessageType::Notice(content) => content.formatted.as_ref(),
+            MessageType::Emote(content) => content.formatted.as_ref(),
+            _ => return false,
+        };
+        self.msgtype.body().trim().is_empty()
+            && formatted.is_none_or(|formatted| formatted.body.trim().is_empty())
+    }
+
     pub fn apply_edit(&mut self, mut new_content: RoomMessageEventContentWithoutRelation) {
         self.edited = true;
+        // The mentions of the replacement are the ones that apply.
+        self.mentions = new_content.mentions.take();
         new_content.msgtype.sanitize(
             ruma::html::HtmlSanitizerMode::Compat,
             RemoveReplyFallback::No,
@@ -270,8 +735,254 @@ impl Message {
 #[derive(Debug, Clone, Default)]
 pub struct ReactionsByKeyBySender(pub BTreeMap<String, BTreeMap<OwnedUserId, ReactionInfo>>);
 
+impl ReactionsByKeyBySender {
+    /// The reactions in display order: most used first, with ties broken by
+    /// which key was first reacted with, and then by the key itself so the
+    /// order is the same on every render.
+    pub fn sorted(&self) -> Vec<SortedReaction<'_>> {
+        let first_reacted = |senders: &BTreeMap<OwnedUserId, ReactionInfo>| {
+            senders.values().map(|info| info.timestamp).min()
+        };
+        let mut reactions: Vec<_> = self.0.iter().collect();
+        reactions.sort_by(|(_, a), (_, b)| {
+            b.len()
+                .cmp(&a.len())
+                .then_with(|| first_reacted(a).cmp(&first_reacted(b)))
+        });
+        // Keys are already in order, as they come from a `BTreeMap` and the
+        // sort is stable.
+        reactions
+    }
+
+    /// The reactions in display order, split into the first `limit` and the
+    /// rest. A `limit` of 0 puts every reaction in the first part.
+    pub fn sorted_with_overflow(
+        &self,
+        limit: usize,
+    ) -> (Vec<SortedReaction<'_>>, Vec<SortedReaction<'_>>) {
+        let mut shown = self.sorted();
+        let overflow = if limit == 0 || shown.len() <= limit {
+            Vec::new()
+        } else {
+            shown.split_off(limit)
+        };
+        (shown, overflow)
+    }
+}
+
+/// A reaction key with everyone who reacted with it.
+pub type SortedReaction<'a> = (&'a String, &'a BTreeMap<OwnedUserId, ReactionInfo>);
+
 /// Information about a single reaction stored in [`ReactionsByKeyBySender`].
 #[derive(Clone, Debug)]
 pub struct ReactionInfo {
     pub timestamp: MilliSecondsSinceUnixEpoch,
+    /// The reacting member's profile, as fetched for the reaction event.
+    pub sender_profile: Option<Profile>,
+}
+
+impl ReactionInfo {
+    /// The sender's display name, with their user ID appended if the name is
+    /// shared with another member, or just the user ID if they have no name.
+    pub fn sender_name(&self, sender: &OwnedUserId) -> String {
+        match &self.sender_profile {
+            Some(Profile {
+                display_name: Some(display_name),
+                display_name_ambiguous,
+                ..
+            }) => {
+                if *display_name_ambiguous {
+                    format!("{display_name} ({sender})")
+                } else {
+                    display_name.clone()
+                }
+            }
+            _ => sender.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{UInt, events::room::message::ImageMessageEventContent, mxc_uri, owned_user_id};
+    use serde_json::json;
+
+    use super::*;
+
+    /// A timeline event with `content`, built from its raw JSON.
+    fn timeline_event(raw: serde_json::Value, content: TimelineItemContent) -> TimelineEvent {
+        TimelineEvent {
+            event_id: raw["event_id"]
+                .as_str()
+                .map(|event_id| event_id.try_into().unwrap()),
+            sender: raw["sender"].as_str().unwrap().try_into().unwrap(),
+            sender_profile: None,
+            timestamp: MilliSecondsSinceUnixEpoch(
+                UInt::new(raw["origin_server_ts"].as_u64().unwrap()).unwrap(),
+            ),
+            content,
+            is_room_encrypted: false,
+            bridged_sender: None,
+            origin_server: None,
+            is_highlighted: false,
+            gate_media: false,
+            sent_before_join: false,
+            raw: serde_json::value::to_raw_value(&raw).unwrap(),
+        }
+    }
+
+    fn msg_like(kind: MsgLikeKind) -> TimelineItemContent {
+        TimelineItemContent::MsgLike(MsgLikeContent {
+            kind,
+            reactions: ReactionsByKeyBySender::default(),
+            in_reply_to: None,
+            thread_root: None,
+            thread_reply_count: None,
+        })
+    }
+
+    fn text_event(event_id: &str, timestamp: u64) -> TimelineEvent {
+        timeline_event(
+            json!({
+                "type": "m.room.message",
+                "event_id": event_id,
+                "sender": "@alice:example.org",
+                "origin_server_ts": timestamp,
+                "content": { "msgtype": "m.text", "body": "hello" },
+            }),
+            msg_like(MsgLikeKind::Message(message(MessageType::text_plain(
+                "hello",
+            )))),
+        )
+    }
+
+    fn reaction_event(event_id: &str, sender: &str, timestamp: u64, key: &str) -> TimelineEvent {
+        timeline_event(
+            json!({
+                "type": "m.reaction",
+                "event_id": event_id,
+                "sender": sender,
+                "origin_server_ts": timestamp,
+                "content": {
+                    "m.relates_to": {
+                        "rel_type": "m.annotation",
+                        "event_id": "$target",
+                        "key": key,
+                    },
+                },
+            }),
+            msg_like(MsgLikeKind::Hidden),
+        )
+    }
+
+    fn reactions(event: &TimelineEvent) -> &BTreeMap<String, BTreeMap<OwnedUserId, ReactionInfo>> {
+        match &event.content {
+            TimelineItemContent::MsgLike(msg_like) => &msg_like.reactions.0,
+            _ => panic!("not a message-like event"),
+        }
+    }
+
+    fn message(msgtype: MessageType) -> Message {
+        Message::from_event(msgtype, None, None)
+    }
+
+    #[test]
+    fn empty_body_is_empty() {
+        assert!(message(MessageType::text_plain("")).is_empty());
+    }
+
+    #[test]
+    fn whitespace_only_body_is_empty() {
+        assert!(message(MessageType::text_plain(" \n\t ")).is_empty());
+        assert!(message(MessageType::notice_plain("  ")).is_empty());
+    }
+
+    #[test]
+    fn text_body_is_not_empty() {
+        assert!(!message(MessageType::text_plain("hello")).is_empty());
+        assert!(!message(MessageType::text_html(" ", "<b>hello</b>")).is_empty());
+    }
+
+    #[test]
+    fn attachment_without_body_is_not_empty() {
+        let image = ImageMessageEventContent::plain(
+            String::new(),
+            mxc_uri!("mxc://example.org/image").to_owned(),
+        );
+        assert!(!message(MessageType::Image(image)).is_empty());
+    }
+
+    #[test]
+    fn reactions_are_counted_towards_their_target() {
+        let mut events = vec![
+            text_event("$target", 1),
+            reaction_event("$a", "@alice:example.org", 2, "👍"),
+            reaction_event("$b", "@bob:example.org", 3, "👍"),
+        ];
+        aggregate_reactions(&mut events);
+        let reactions = reactions(&events[0]);
+        assert_eq!(reactions.len(), 1);
+        assert_eq!(reactions[&normalize_reaction_key("👍")].len(), 2);
+    }
+
+    #[test]
+    fn redacted_reactions_and_empty_keys_are_not_counted() {
+        let redacted = timeline_event(
+            json!({
+                "type": "m.reaction",
+                "event_id": "$redacted",
+                "sender": "@bob:example.org",
+                "origin_server_ts": 3,
+                "content": {},
+                "unsigned": {
+                    "redacted_because": {
+                        "type": "m.room.redaction",
+                        "event_id": "$redaction",
+                        "sender": "@bob:example.org",
+                        "origin_server_ts": 4,
+                        "redacts": "$redacted",
+                        "content": { "redacts": "$redacted" },
+                    },
+                },
+            }),
+            msg_like(MsgLikeKind::Hidden),
+        );
+        let mut events = vec![
+            text_event("$target", 1),
+            redacted,
+            reaction_event("$empty", "@alice:example.org", 5, ""),
+            reaction_event("$blank", "@alice:example.org", 6, " "),
+        ];
+        aggregate_reactions(&mut events);
+        assert!(reactions(&events[0]).is_empty());
+    }
+
+    #[test]
+    fn earliest_reaction_per_sender_is_kept() {
+        let mut events = vec![
+            text_event("$target", 1),
+            reaction_event("$late", "@alice:example.org", 9, "👍"),
+            reaction_event("$early", "@alice:example.org", 4, "👍"),
+        ];
+        aggregate_reactions(&mut events);
+        let senders = &reactions(&events[0])[&normalize_reaction_key("👍")];
+        assert_eq!(senders.len(), 1);
+        let alice = owned_user_id!("@alice:example.org");
+        assert_eq!(senders[&alice].timestamp.get(), UInt::new(4).unwrap());
+    }
+
+    #[test]
+    fn repeated_events_are_removed() {
+        let mut events = vec![
+            text_event("$first", 1),
+            text_event("$second", 2),
+            text_event("$first", 1),
+        ];
+        dedup_events(&mut events);
+        let event_ids: Vec<_> = events
+            .iter()
+            .map(|event| event.event_id.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(event_ids, ["$first", "$second"]);
+    }
 }